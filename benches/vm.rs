@@ -0,0 +1,115 @@
+// How fast the VM runs: the interpreter against compiled genes, and a
+// batch of 10k cells. Run with cargo bench.
+
+use std::time::{Duration, Instant};
+
+use bevy::tasks::TaskPoolBuilder;
+use caldo_bevy::assembler::load;
+use caldo_bevy::batch::Batch;
+use caldo_bevy::compiled::CompiledCell;
+use caldo_bevy::data::{Cell, Processor};
+use caldo_bevy::encoding::{decode_genes, OPCODES};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const STEPS: usize = 100_000;
+
+fn replicator() -> Cell {
+    let mut cell = load(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/genomes/replicator.txt"
+    ))
+    .unwrap();
+    cell.add_materials(1_000_000);
+    cell
+}
+
+fn random_cells(amount: usize) -> Vec<Cell> {
+    let mut rng = StdRng::seed_from_u64(0);
+    (0..amount)
+        .map(|_i| {
+            let bytes: Vec<u8> = (0..600).map(|_i| rng.gen()).collect();
+            let mut cell = decode_genes(&bytes, OPCODES.len() as u8, Default::default());
+            cell.add_materials(1000);
+            cell
+        })
+        .collect()
+}
+
+fn interpreted(cells: &[Cell], steps: usize) -> Duration {
+    let mut cells = cells.to_vec();
+    let start = Instant::now();
+    for cell in cells.iter_mut() {
+        let mut processor = Processor::new();
+        processor.execute(cell, steps);
+    }
+    start.elapsed()
+}
+
+fn compiled(cells: &[Cell], steps: usize) -> Duration {
+    let mut cells: Vec<CompiledCell> = cells.iter().cloned().map(CompiledCell::new).collect();
+    let start = Instant::now();
+    for cell in cells.iter_mut() {
+        let mut processor = Processor::new();
+        cell.execute(&mut processor, steps);
+    }
+    start.elapsed()
+}
+
+fn batched(batch: &Batch, steps: usize, threads: Option<usize>) -> Duration {
+    let mut batch = batch.clone();
+    let pool = threads.map(|threads| TaskPoolBuilder::new().num_threads(threads).build());
+    let start = Instant::now();
+    match &pool {
+        Some(pool) => batch.execute_parallel(steps, pool),
+        None => batch.execute(steps),
+    }
+    start.elapsed()
+}
+
+// The fastest of a few runs, as other work on the machine only makes runs
+// slower.
+fn fastest(run: impl Fn() -> Duration) -> Duration {
+    (0..5).map(|_i| run()).min().unwrap()
+}
+
+fn report(name: &str, cells: &[Cell], steps: usize) {
+    let instructions = (cells.len() * steps) as f64;
+    let interpreted = fastest(|| interpreted(cells, steps));
+    let compiled = fastest(|| compiled(cells, steps));
+    println!(
+        "{}: interpreted {:.1} ns, compiled {:.1} ns per instruction, {:.2}x",
+        name,
+        interpreted.as_nanos() as f64 / instructions,
+        compiled.as_nanos() as f64 / instructions,
+        interpreted.as_secs_f64() / compiled.as_secs_f64()
+    );
+}
+
+// A frame's worth of instructions for many cells, as the world runs them.
+fn report_batch(cells: &[Cell], steps: usize) {
+    let instructions = (cells.len() * steps) as f64;
+    let mut batch = Batch::new();
+    for cell in cells {
+        batch.push(cell.clone(), Processor::new()).unwrap();
+    }
+    let interpreted = fastest(|| interpreted(cells, steps));
+    let compiled = fastest(|| compiled(cells, steps));
+    let sequential = fastest(|| batched(&batch, steps, None));
+    let parallel = fastest(|| batched(&batch, steps, Some(4)));
+    println!(
+        "batch of {}: interpreted {:.1} ns, compiled {:.1} ns, batch {:.1} ns, \
+         batch on 4 threads {:.1} ns per instruction",
+        cells.len(),
+        interpreted.as_nanos() as f64 / instructions,
+        compiled.as_nanos() as f64 / instructions,
+        sequential.as_nanos() as f64 / instructions,
+        parallel.as_nanos() as f64 / instructions
+    );
+}
+
+fn main() {
+    report("replicator", &[replicator()], STEPS * 10);
+    report("random genomes", &random_cells(1000), STEPS / 100);
+    report_batch(&random_cells(10_000), 100);
+}
@@ -0,0 +1,559 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::data::{trimmed_length, Addressing, Cell, Instr, StackPolicy, VmConfig};
+
+// The textual genome format looks like this:
+//
+// == 0, Main
+// =5    // push a number
+// =1
+// Call  // call gene 1
+//
+// == 1 add three
+// =3
+// Add
+//
+// A gene starts with a `==` header followed by its index; anything after
+// the index is a description and is ignored. `//` starts a comment.
+// Genes that aren't mentioned are filled with Noop, as are the slots after
+// the last instruction of a gene.
+//
+// A cell with another config than the default starts with a config line
+// before the first gene, like:
+//
+// config gene_size=5 gene_amount=3 addressing=Templates
+//
+// Settings that aren't mentioned keep the value of the config given to
+// assemble_with_config.
+
+#[derive(Debug)]
+pub enum AssembleError {
+    Io(io::Error),
+    MissingGeneHeader { line: usize },
+    BadGeneHeader { line: usize },
+    BadConfig { line: usize },
+    DuplicateGene { line: usize, gene_index: usize },
+    UnknownInstruction { line: usize, word: String },
+    GeneTooLong { line: usize, gene_index: usize },
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssembleError::Io(err) => write!(f, "cannot read genome: {}", err),
+            AssembleError::MissingGeneHeader { line } => {
+                write!(f, "line {}: instruction before first gene header", line)
+            }
+            AssembleError::BadGeneHeader { line } => {
+                write!(f, "line {}: gene header needs a valid gene index", line)
+            }
+            AssembleError::BadConfig { line } => {
+                write!(f, "line {}: config has to come first and be valid", line)
+            }
+            AssembleError::DuplicateGene { line, gene_index } => {
+                write!(f, "line {}: gene {} defined twice", line, gene_index)
+            }
+            AssembleError::UnknownInstruction { line, word } => {
+                write!(f, "line {}: unknown instruction {:?}", line, word)
+            }
+            AssembleError::GeneTooLong { line, gene_index } => {
+                write!(
+                    f,
+                    "line {}: gene {} has too many instructions",
+                    line, gene_index
+                )
+            }
+        }
+    }
+}
+
+impl Error for AssembleError {}
+
+impl From<io::Error> for AssembleError {
+    fn from(err: io::Error) -> AssembleError {
+        AssembleError::Io(err)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnknownInstruction;
+
+impl FromStr for Instr {
+    type Err = UnknownInstruction;
+
+    fn from_str(s: &str) -> Result<Instr, UnknownInstruction> {
+        if let Some(number) = s.strip_prefix('=') {
+            return number
+                .parse::<u8>()
+                .map(Instr::Number)
+                .map_err(|_| UnknownInstruction);
+        }
+        let instr = match s {
+            "Zero" => Instr::Zero,
+            "Noop" => Instr::Noop,
+            "Nop0" => Instr::Nop0,
+            "Nop1" => Instr::Nop1,
+            "Add" => Instr::Add,
+            "Sub" => Instr::Sub,
+            "Mul" => Instr::Mul,
+            "Div" => Instr::Div,
+            "Mod" => Instr::Mod,
+            "Min" => Instr::Min,
+            "Max" => Instr::Max,
+            "Inc" => Instr::Inc,
+            "Dec" => Instr::Dec,
+            "BitAnd" => Instr::BitAnd,
+            "BitOr" => Instr::BitOr,
+            "BitXor" => Instr::BitXor,
+            "Shl" => Instr::Shl,
+            "Shr" => Instr::Shr,
+            "Eq" => Instr::Eq,
+            "Ne" => Instr::Ne,
+            "Gt" => Instr::Gt,
+            "Lt" => Instr::Lt,
+            "And" => Instr::And,
+            "Or" => Instr::Or,
+            "Not" => Instr::Not,
+            "Dup" => Instr::Dup,
+            "Drop" => Instr::Drop,
+            "Swap" => Instr::Swap,
+            "Over" => Instr::Over,
+            "Dup2" => Instr::Dup2,
+            "Drop2" => Instr::Drop2,
+            "Rot" => Instr::Rot,
+            "Call" => Instr::Call,
+            "Return" => Instr::Return,
+            "Cond" => Instr::Cond,
+            "Label" => Instr::Label,
+            "Jump" => Instr::Jump,
+            "JumpRel" => Instr::JumpRel,
+            "JumpAbs" => Instr::JumpAbs,
+            "SetLoop" => Instr::SetLoop,
+            "Loop" => Instr::Loop,
+            "Read" => Instr::Read,
+            "Write" => Instr::Write,
+            "Load" => Instr::Load,
+            "Store" => Instr::Store,
+            "TestAndSet" => Instr::TestAndSet,
+            "PopInstr" => Instr::PopInstr,
+            "PushInstr" => Instr::PushInstr,
+            "WriteGene" => Instr::WriteGene,
+            "SelectOut" => Instr::SelectOut,
+            "Out" => Instr::Out,
+            "OutFull" => Instr::OutFull,
+            "SelectIn" => Instr::SelectIn,
+            "In" => Instr::In,
+            "InEmpty" => Instr::InEmpty,
+            "Rand" => Instr::Rand,
+            "RandRange" => Instr::RandRange,
+            "OnContact" => Instr::OnContact,
+            "OnInput" => Instr::OnInput,
+            "SetTimer" => Instr::SetTimer,
+            _ => return Err(UnknownInstruction),
+        };
+        Ok(instr)
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instr::Number(n) => write!(f, "={}", n),
+            // the mnemonics are the variant names
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+pub fn assemble(text: &str) -> Result<Cell, AssembleError> {
+    assemble_with_config(text, VmConfig::default())
+}
+
+pub fn assemble_with_config(text: &str, config: VmConfig) -> Result<Cell, AssembleError> {
+    let mut config = config;
+    let mut cell = Cell::with_config(config);
+    let mut seen = vec![false; config.gene_amount];
+    let mut current: Option<(usize, Vec<Instr>)> = None;
+    let mut config_seen = false;
+
+    for (i, raw_line) in text.lines().enumerate() {
+        let line = i + 1;
+        let code = match raw_line.find("//") {
+            Some(position) => &raw_line[..position],
+            None => raw_line,
+        }
+        .trim();
+        if code.is_empty() {
+            continue;
+        }
+        if let Some(settings) = code.strip_prefix("config ") {
+            if config_seen || current.is_some() {
+                return Err(AssembleError::BadConfig { line });
+            }
+            config_seen = true;
+            config = parse_config(settings, config).ok_or(AssembleError::BadConfig { line })?;
+            cell = Cell::with_config(config);
+            seen = vec![false; config.gene_amount];
+            continue;
+        }
+        if let Some(header) = code.strip_prefix("==") {
+            let gene_index = parse_gene_header(header, config.gene_amount)
+                .ok_or(AssembleError::BadGeneHeader { line })?;
+            if seen[gene_index] {
+                return Err(AssembleError::DuplicateGene { line, gene_index });
+            }
+            seen[gene_index] = true;
+            if let Some((index, instructions)) = current.take() {
+                cell.set_gene(index as u8, instructions);
+            }
+            current = Some((gene_index, Vec::new()));
+            continue;
+        }
+        let (gene_index, instructions) = match current.as_mut() {
+            Some((gene_index, instructions)) => (*gene_index, instructions),
+            None => return Err(AssembleError::MissingGeneHeader { line }),
+        };
+        for word in code.split_whitespace() {
+            let instr = word
+                .parse::<Instr>()
+                .map_err(|_| AssembleError::UnknownInstruction {
+                    line,
+                    word: word.to_string(),
+                })?;
+            if instructions.len() >= config.gene_size {
+                return Err(AssembleError::GeneTooLong { line, gene_index });
+            }
+            instructions.push(instr);
+        }
+    }
+    if let Some((index, instructions)) = current {
+        cell.set_gene(index as u8, instructions);
+    }
+    Ok(cell)
+}
+
+fn parse_gene_header(header: &str, gene_amount: usize) -> Option<usize> {
+    let header = header.trim_start();
+    let end = header
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(header.len());
+    let gene_index = header[..end].parse::<usize>().ok()?;
+    if gene_index < gene_amount {
+        Some(gene_index)
+    } else {
+        None
+    }
+}
+
+// Settings as name=value words, see the format at the top.
+fn parse_config(settings: &str, mut config: VmConfig) -> Option<VmConfig> {
+    for setting in settings.split_whitespace() {
+        let (name, value) = setting.split_once('=')?;
+        match name {
+            "gene_size" => config.gene_size = value.parse().ok()?,
+            "gene_amount" => config.gene_amount = value.parse().ok()?,
+            "label_amount" => config.label_amount = value.parse().ok()?,
+            "data_stack_size" => config.data_stack_size = value.parse().ok()?,
+            "instruction_stack_size" => config.instruction_stack_size = value.parse().ok()?,
+            "call_stack_size" => config.call_stack_size = value.parse().ok()?,
+            "addressing" => {
+                config.addressing = match value {
+                    "Labels" => Addressing::Labels,
+                    "Templates" => Addressing::Templates,
+                    _ => return None,
+                }
+            }
+            "stack_policy" => {
+                config.stack_policy = match value {
+                    "Compress" => StackPolicy::Compress,
+                    "Wrap" => StackPolicy::Wrap,
+                    "DropNewest" => StackPolicy::DropNewest,
+                    "Fault" => StackPolicy::Fault,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+    }
+    if config.is_valid() {
+        Some(config)
+    } else {
+        None
+    }
+}
+
+fn format_config(config: VmConfig) -> String {
+    format!(
+        "config gene_size={} gene_amount={} label_amount={} data_stack_size={} \
+         instruction_stack_size={} call_stack_size={} addressing={:?} stack_policy={:?}\n",
+        config.gene_size,
+        config.gene_amount,
+        config.label_amount,
+        config.data_stack_size,
+        config.instruction_stack_size,
+        config.call_stack_size,
+        config.addressing,
+        config.stack_policy
+    )
+}
+
+pub fn disassemble(cell: &Cell) -> String {
+    let mut text = String::new();
+    if cell.config() != VmConfig::default() {
+        text.push_str(&format_config(cell.config()));
+    }
+    for gene_index in 0..cell.gene_amount() {
+        let gene = cell.gene(gene_index as u8);
+        let length = trimmed_length(gene);
+        if length == 0 {
+            continue;
+        }
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(&format!("== {}\n", gene_index));
+        for instr in &gene[..length] {
+            text.push_str(&format!("{}\n", instr));
+        }
+    }
+    text
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<Cell, AssembleError> {
+    let text = fs::read_to_string(path)?;
+    assemble(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::GENE_SIZE;
+    use crate::encoding::OPCODES;
+
+    #[test]
+    fn test_instr_from_str() {
+        assert_eq!("Add".parse::<Instr>(), Ok(Instr::Add));
+        assert_eq!("Dup2".parse::<Instr>(), Ok(Instr::Dup2));
+        assert_eq!("=5".parse::<Instr>(), Ok(Instr::Number(5)));
+        assert_eq!("=255".parse::<Instr>(), Ok(Instr::Number(255)));
+        assert_eq!("=256".parse::<Instr>(), Err(UnknownInstruction));
+        assert_eq!("add".parse::<Instr>(), Err(UnknownInstruction));
+    }
+
+    #[test]
+    fn test_instr_display() {
+        assert_eq!(Instr::Number(5).to_string(), "=5");
+        assert_eq!(Instr::Dup2.to_string(), "Dup2");
+    }
+
+    #[test]
+    fn test_assemble() {
+        let cell = assemble(
+            "
+== 0, Main
+=5
+=1
+Call // call gene 1
+
+== 1 add three, invoked with a number on the stack
+=3 Add
+",
+        )
+        .unwrap();
+        let mut expected = Cell::new();
+        expected.set_gene(0, vec![Instr::Number(5), Instr::Number(1), Instr::Call]);
+        expected.set_gene(1, vec![Instr::Number(3), Instr::Add]);
+        assert_eq!(cell, expected);
+    }
+
+    #[test]
+    fn test_assemble_empty() {
+        assert_eq!(assemble("// nothing here\n").unwrap(), Cell::new());
+    }
+
+    #[test]
+    fn test_assemble_missing_header() {
+        match assemble("Add") {
+            Err(AssembleError::MissingGeneHeader { line: 1 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_assemble_bad_header() {
+        match assemble("== 16") {
+            Err(AssembleError::BadGeneHeader { line: 1 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match assemble("== main") {
+            Err(AssembleError::BadGeneHeader { line: 1 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_assemble_duplicate_gene() {
+        match assemble("== 1\nAdd\n== 1\n") {
+            Err(AssembleError::DuplicateGene {
+                line: 3,
+                gene_index: 1,
+            }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_assemble_unknown_instruction() {
+        match assemble("== 0\nAdd\nFoo") {
+            Err(AssembleError::UnknownInstruction { line: 3, word }) => assert_eq!(word, "Foo"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_assemble_gene_too_long() {
+        let text = format!("== 2\n{}", "Dup\n".repeat(GENE_SIZE + 1));
+        match assemble(&text) {
+            Err(AssembleError::GeneTooLong {
+                line,
+                gene_index: 2,
+            }) => assert_eq!(line, GENE_SIZE + 2),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_assemble_with_config() {
+        let config = VmConfig {
+            gene_size: 2,
+            gene_amount: 4,
+            ..VmConfig::default()
+        };
+        let cell = assemble_with_config("== 3\nDup Add", config).unwrap();
+        assert_eq!(cell.gene(3), &[Instr::Dup, Instr::Add]);
+        match assemble_with_config("== 4\nDup", config) {
+            Err(AssembleError::BadGeneHeader { line: 1 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match assemble_with_config("== 0\nDup Add Dup", config) {
+            Err(AssembleError::GeneTooLong {
+                line: 2,
+                gene_index: 0,
+            }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_assemble_config() {
+        let cell = assemble("config gene_size=2 addressing=Templates\n== 0\nDup Add").unwrap();
+        assert_eq!(
+            cell.config(),
+            VmConfig {
+                gene_size: 2,
+                addressing: Addressing::Templates,
+                ..VmConfig::default()
+            }
+        );
+        assert_eq!(cell.gene(0), &[Instr::Dup, Instr::Add]);
+        for text in &[
+            "config gene_size=0",
+            "config gene_size=two",
+            "config colour=red",
+            "config stack_policy=Never",
+            "config gene_size=2\nconfig gene_amount=2",
+            "== 0\nconfig gene_size=2",
+        ] {
+            match assemble(text) {
+                Err(AssembleError::BadConfig { .. }) => {}
+                other => panic!("unexpected result {:?} for {:?}", other, text),
+            }
+        }
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut cell = Cell::new();
+        cell.set_gene(0, vec![Instr::Number(5), Instr::Noop, Instr::Add]);
+        cell.set_gene(3, vec![Instr::Return]);
+        assert_eq!(disassemble(&cell), "== 0\n=5\nNoop\nAdd\n\n== 3\nReturn\n");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut cell = Cell::new();
+        cell.set_gene(
+            0,
+            vec![
+                Instr::Number(0),
+                Instr::Number(255),
+                Instr::Noop,
+                Instr::Label,
+                Instr::Jump,
+            ],
+        );
+        cell.set_gene(7, vec![Instr::Dup2, Instr::Drop2, Instr::Cond]);
+        cell.set_gene(15, vec![Instr::Over; GENE_SIZE]);
+        assert_eq!(assemble(&disassemble(&cell)).unwrap(), cell);
+    }
+
+    #[test]
+    fn test_round_trip_config() {
+        let config = VmConfig {
+            gene_size: 5,
+            gene_amount: 3,
+            label_amount: 2,
+            data_stack_size: 7,
+            instruction_stack_size: 6,
+            call_stack_size: 9,
+            addressing: Addressing::Templates,
+            stack_policy: StackPolicy::Fault,
+        };
+        let mut cell = Cell::with_config(config);
+        cell.set_gene(2, vec![Instr::Nop1, Instr::Call]);
+        let text = disassemble(&cell);
+        assert!(text.starts_with("config gene_size=5 gene_amount=3 "));
+        assert_eq!(assemble(&text).unwrap(), cell);
+    }
+
+    #[test]
+    fn test_round_trip_every_number() {
+        let mut cell = Cell::new();
+        for gene_index in 0..8 {
+            let instructions = (0..GENE_SIZE)
+                .map(|i| Instr::Number((gene_index * GENE_SIZE + i) as u8))
+                .collect();
+            cell.set_gene(gene_index as u8, instructions);
+        }
+        assert_eq!(assemble(&disassemble(&cell)).unwrap(), cell);
+    }
+
+    #[test]
+    fn test_round_trip_every_instruction() {
+        for instr in OPCODES.iter() {
+            assert_eq!(instr.to_string().parse::<Instr>(), Ok(*instr));
+        }
+        let mut cell = Cell::new();
+        for (gene_index, instructions) in OPCODES.chunks(GENE_SIZE).enumerate() {
+            cell.set_gene(gene_index as u8, instructions.to_vec());
+        }
+        assert_eq!(assemble(&disassemble(&cell)).unwrap(), cell);
+    }
+
+    #[test]
+    fn test_load_sketch() {
+        let cell = load(concat!(env!("CARGO_MANIFEST_DIR"), "/genomes/sketch.txt")).unwrap();
+        assert_eq!(
+            &cell.gene(0)[..3],
+            &[Instr::Zero, Instr::SetLoop, Instr::Dup]
+        );
+        assert_eq!(cell.gene(1)[2], Instr::Call);
+        assert_eq!(cell.gene(3)[3], Instr::Write);
+        assert_eq!(assemble(&disassemble(&cell)).unwrap(), cell);
+    }
+}
@@ -0,0 +1,238 @@
+use bevy::tasks::TaskPool;
+
+use crate::compiled::CompiledCell;
+use crate::data::{Cell, Processor, ProcessorColumns, VmConfig};
+
+// How many processors a block of columns holds. Blocks are what the
+// threads divide between them.
+const BLOCK_SIZE: usize = 256;
+
+// Many cells, each with a processor, run together. The genes are compiled,
+// and the processors are kept as columns (see ProcessorColumns) in blocks
+// of BLOCK_SIZE, so all processors have the config of the batch. Each
+// processor only sees its own cell, so running them in parallel gives the
+// same results as running them one by one with Processor::execute.
+// benches/vm.rs runs 10k cells.
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    config: VmConfig,
+    cells: Vec<CompiledCell>,
+    blocks: Vec<ProcessorColumns>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    pub fn with_config(config: VmConfig) -> Batch {
+        Batch {
+            config,
+            ..Batch::default()
+        }
+    }
+
+    pub fn config(&self) -> VmConfig {
+        self.config
+    }
+
+    // Returns the index of the cell and its processor, or None if the
+    // processor has another config than the batch. A cell with another
+    // config makes its processor fault, like Processor::execute does.
+    pub fn push(&mut self, cell: Cell, processor: Processor) -> Option<usize> {
+        if processor.config() != self.config {
+            return None;
+        }
+        if self
+            .blocks
+            .last()
+            .is_none_or(|block| block.len() == BLOCK_SIZE)
+        {
+            self.blocks.push(ProcessorColumns::default());
+        }
+        self.blocks.last_mut().unwrap().push(&processor);
+        self.cells.push(CompiledCell::new(cell));
+        Some(self.cells.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn cell(&self, index: usize) -> &Cell {
+        self.cells[index].cell()
+    }
+
+    pub fn cell_mut(&mut self, index: usize) -> &mut Cell {
+        self.cells[index].cell_mut()
+    }
+
+    // The processor put together from its columns.
+    pub fn processor(&self, index: usize) -> Processor {
+        let mut processor = Processor::with_config(self.config);
+        self.blocks[index / BLOCK_SIZE].load(index % BLOCK_SIZE, &mut processor);
+        processor
+    }
+
+    // Run every processor for amount instructions.
+    pub fn execute(&mut self, amount: usize) {
+        let cells = self.cells.chunks_mut(BLOCK_SIZE);
+        for (cells, columns) in cells.zip(&mut self.blocks) {
+            execute_block(cells, columns, self.config, amount);
+        }
+    }
+
+    // Like execute, but the blocks are spread over the threads of the pool.
+    pub fn execute_parallel(&mut self, amount: usize, pool: &TaskPool) {
+        let config = self.config;
+        let blocks_per_task = (self.blocks.len() / pool.thread_num()).max(1);
+        let cells = self.cells.chunks_mut(blocks_per_task * BLOCK_SIZE);
+        let blocks = self.blocks.chunks_mut(blocks_per_task);
+        pool.scope(|scope| {
+            for (cells, blocks) in cells.zip(blocks) {
+                scope.spawn(async move {
+                    for (cells, columns) in cells.chunks_mut(BLOCK_SIZE).zip(blocks) {
+                        execute_block(cells, columns, config, amount);
+                    }
+                });
+            }
+        });
+    }
+}
+
+// The processors are loaded one by one into a single processor, which runs
+// them and is stored back. Stopped processors don't run, so they aren't
+// loaded at all.
+fn execute_block(
+    cells: &mut [CompiledCell],
+    columns: &mut ProcessorColumns,
+    config: VmConfig,
+    amount: usize,
+) {
+    let mut processor = Processor::with_config(config);
+    for (index, cell) in cells.iter_mut().enumerate() {
+        if !columns.is_active(index) {
+            continue;
+        }
+        columns.load(index, &mut processor);
+        cell.execute(&mut processor, amount);
+        columns.store(index, &processor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Instr;
+    use crate::encoding::{decode_genes, OPCODES};
+    use bevy::tasks::TaskPoolBuilder;
+    use proptest::prelude::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn cells(genomes: &[Vec<u8>]) -> Vec<Cell> {
+        genomes
+            .iter()
+            .map(|bytes| {
+                let mut cell = decode_genes(bytes, OPCODES.len() as u8, Default::default());
+                cell.add_materials(50);
+                cell
+            })
+            .collect()
+    }
+
+    fn batch(cells: &[Cell]) -> Batch {
+        let mut batch = Batch::new();
+        for (i, cell) in cells.iter().enumerate() {
+            let mut processor = Processor::new();
+            processor.set_seed(i as u64);
+            batch.push(cell.clone(), processor).unwrap();
+        }
+        batch
+    }
+
+    // Runs the cells in a batch, in a batch on threads and one by one.
+    fn check_same_as_execute(cells: Vec<Cell>, threads: usize) {
+        let mut sequential = batch(&cells);
+        sequential.execute(300);
+        let mut parallel = batch(&cells);
+        let pool = TaskPoolBuilder::new().num_threads(threads).build();
+        parallel.execute_parallel(300, &pool);
+        for (i, mut cell) in cells.into_iter().enumerate() {
+            let mut processor = Processor::new();
+            processor.set_seed(i as u64);
+            processor.execute(&mut cell, 300);
+            assert_eq!(sequential.processor(i), processor);
+            assert_eq!(sequential.cell(i), &cell);
+            assert_eq!(parallel.processor(i), processor);
+            assert_eq!(parallel.cell(i), &cell);
+        }
+    }
+
+    #[test]
+    fn test_push() {
+        let mut batch = Batch::new();
+        assert!(batch.is_empty());
+        assert_eq!(batch.push(Cell::new(), Processor::new()), Some(0));
+        let mut processor = Processor::new();
+        processor.set_seed(3);
+        processor.start(2);
+        assert_eq!(batch.push(Cell::new(), processor.clone()), Some(1));
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.processor(1), processor);
+    }
+
+    #[test]
+    fn test_push_other_config() {
+        let config = VmConfig {
+            data_stack_size: 4,
+            ..VmConfig::default()
+        };
+        let mut batch = Batch::with_config(config);
+        assert_eq!(batch.push(Cell::new(), Processor::new()), None);
+        assert_eq!(
+            batch.push(Cell::new(), Processor::with_config(config)),
+            Some(0)
+        );
+        // the processor faults on the cell, like it would on its own
+        batch.execute(10);
+        assert!(!batch.processor(0).is_active());
+        assert_eq!(batch.processor(0).faults(), 1);
+    }
+
+    #[test]
+    fn test_stopped() {
+        let mut cell = Cell::new();
+        cell.set_gene(0, vec![Instr::Number(1)]);
+        let mut processor = Processor::new();
+        processor.stop();
+        let mut batch = Batch::new();
+        batch.push(cell.clone(), processor.clone()).unwrap();
+        batch.execute(10);
+        assert_eq!(batch.processor(0), processor);
+        assert_eq!(batch.cell(0), &cell);
+    }
+
+    #[test]
+    fn test_many_blocks() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let genomes: Vec<Vec<u8>> = (0..BLOCK_SIZE * 2 + 10)
+            .map(|_i| (0..200).map(|_i| rng.gen()).collect())
+            .collect();
+        check_same_as_execute(cells(&genomes), 2);
+    }
+
+    proptest! {
+        #[test]
+        fn test_same_as_execute(
+            genomes in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..300), 1..20),
+            threads in 1..5usize,
+        ) {
+            check_same_as_execute(cells(&genomes), threads);
+        }
+    }
+}
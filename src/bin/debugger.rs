@@ -0,0 +1,114 @@
+// A terminal debugger for genomes in the assembler format.
+//
+// cargo run --bin debugger -- genome.txt
+
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+use caldo_bevy::assembler::load;
+use caldo_bevy::debugger::{Debugger, Stop, Watch};
+
+// how long `c` runs before giving up
+const RUN_LIMIT: usize = 100_000;
+
+const HELP: &str = "\
+s [n]           step n instructions (1)
+c               continue until a breakpoint or watch
+b gene pc       add breakpoint
+d gene pc       delete breakpoint
+w depth n       stop when the stack holds n values
+w above n       stop when the stack holds more than n values
+w top n         stop when the top of the stack is n
+w clear         remove all watches
+m n             add n materials to the cell
+g gene          restart the processor on a gene
+v               view the processor
+q               quit";
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: debugger <genome file>");
+            process::exit(2);
+        }
+    };
+    let cell = match load(&path) {
+        Ok(cell) => cell,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        }
+    };
+    let mut debugger = Debugger::new(cell);
+    println!("{}", debugger.view());
+
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match command(&mut debugger, &words) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(message) => println!("{}", message),
+        }
+    }
+}
+
+// Returns false when we should quit.
+fn command(debugger: &mut Debugger, words: &[&str]) -> Result<bool, String> {
+    match words {
+        [] => {}
+        ["q"] => return Ok(false),
+        ["h"] => println!("{}", HELP),
+        ["v"] => println!("{}", debugger.view()),
+        ["s"] => {
+            debugger.step();
+            println!("{}", debugger.view());
+        }
+        ["s", n] => {
+            for _i in 0..number::<usize>(n)? {
+                debugger.step();
+            }
+            println!("{}", debugger.view());
+        }
+        ["c"] => {
+            match debugger.run(RUN_LIMIT) {
+                Stop::Breakpoint { gene_index, pc } => {
+                    println!("breakpoint at gene {} pc {}", gene_index, pc)
+                }
+                Stop::Watch(watch) => println!("watch {:?}", watch),
+                Stop::StepLimit => println!("no stop after {} steps", RUN_LIMIT),
+            }
+            println!("{}", debugger.view());
+        }
+        ["b", gene_index, pc] => debugger.add_breakpoint(number(gene_index)?, number(pc)?),
+        ["d", gene_index, pc] => {
+            if !debugger.remove_breakpoint(number(gene_index)?, number(pc)?) {
+                return Err("no such breakpoint".to_string());
+            }
+        }
+        ["w", "depth", n] => debugger.add_watch(Watch::Depth(number(n)?)),
+        ["w", "above", n] => debugger.add_watch(Watch::DepthAbove(number(n)?)),
+        ["w", "top", n] => debugger.add_watch(Watch::Top(number(n)?)),
+        ["w", "clear"] => debugger.clear_watches(),
+        ["m", n] => debugger.cell_mut().add_materials(number(n)?),
+        ["g", gene_index] => {
+            debugger.processor_mut().start(number(gene_index)?);
+            println!("{}", debugger.view());
+        }
+        _ => return Err("unknown command, h for help".to_string()),
+    }
+    Ok(true)
+}
+
+fn number<T: std::str::FromStr>(word: &str) -> Result<T, String> {
+    word.parse()
+        .map_err(|_| format!("{:?} is not a valid number", word))
+}
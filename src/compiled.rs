@@ -0,0 +1,353 @@
+use crate::data::{Cell, Instr, Processor};
+use crate::encoding::OPCODES;
+
+// Genes compiled to threaded code: each instruction becomes a call to a
+// handler, a function made for just that instruction. Processors run
+// exactly as they do on the cell itself, only faster; benches/vm.rs
+// compares the two.
+
+type Handler = fn(&mut Processor, &mut Cell, u8);
+
+// The handler of the instruction with this opcode: it counts the step
+// and executes the instruction. The instruction is a constant, so once
+// execute_in is inlined only the code for this one instruction is left;
+// there's no dispatch on the instruction inside.
+fn handler<const OPCODE: u8>(processor: &mut Processor, cell: &mut Cell, _argument: u8) {
+    processor.advance(OPCODE);
+    const { OPCODES[OPCODE as usize] }.execute_in(processor, cell);
+}
+
+// The handler for numbers; the argument is the number.
+fn number_handler(processor: &mut Processor, cell: &mut Cell, argument: u8) {
+    processor.advance(0);
+    Instr::Number(argument).execute_in(processor, cell);
+}
+
+macro_rules! handlers {
+    ($($opcode:literal)*) => {
+        (
+            [number_handler as Handler, $(handler::<$opcode> as Handler),*],
+            [0, $($opcode),*],
+        )
+    };
+}
+
+// By opcode, along with the opcodes the handlers were made for. Adding an
+// opcode to OPCODES without adding it here doesn't compile.
+const HANDLERS_BY_OPCODE: ([Handler; OPCODES.len()], [u8; OPCODES.len()]) = handlers!(
+    1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27
+    28 29 30 31 32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51
+    52 53 54 55 56 57 58 59 60
+);
+const HANDLERS: [Handler; OPCODES.len()] = HANDLERS_BY_OPCODE.0;
+
+// every handler is in the place of its own opcode
+const _: () = {
+    let opcodes = HANDLERS_BY_OPCODE.1;
+    let mut i = 0;
+    while i < opcodes.len() {
+        assert!(opcodes[i] as usize == i, "handlers out of order");
+        i += 1;
+    }
+};
+
+// How an instruction changes the genes, so we know what to compile again.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Writes {
+    Nothing,
+    // Write, to the slot its arguments point to
+    Slot,
+    // WriteGene, to the whole gene
+    Gene,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Op {
+    handler: Handler,
+    argument: u8,
+    writes: Writes,
+}
+
+impl Op {
+    fn new(instr: Instr) -> Op {
+        Op {
+            handler: HANDLERS[instr.opcode() as usize],
+            argument: match instr {
+                Instr::Number(n) => n,
+                _ => 0,
+            },
+            writes: match instr {
+                Instr::Write => Writes::Slot,
+                Instr::WriteGene => Writes::Gene,
+                _ => Writes::Nothing,
+            },
+        }
+    }
+}
+
+// A cell along with its compiled genes. A gene is compiled again when a
+// processor writes to it; changing the cell through cell_mut compiles all
+// of them again.
+#[derive(Debug, Clone)]
+pub struct CompiledCell {
+    cell: Cell,
+    // all genes one after the other, like in the cell
+    ops: Vec<Op>,
+    stale: bool,
+}
+
+impl CompiledCell {
+    pub fn new(cell: Cell) -> CompiledCell {
+        let mut compiled = CompiledCell {
+            cell,
+            ops: Vec::new(),
+            stale: true,
+        };
+        compiled.compile();
+        compiled
+    }
+
+    pub fn cell(&self) -> &Cell {
+        &self.cell
+    }
+
+    pub fn cell_mut(&mut self) -> &mut Cell {
+        self.stale = true;
+        &mut self.cell
+    }
+
+    pub fn into_cell(self) -> Cell {
+        self.cell
+    }
+
+    fn compile(&mut self) {
+        self.ops.clear();
+        for gene_index in 0..self.cell.gene_amount() {
+            let gene = self.cell.gene(gene_index as u8);
+            self.ops.extend(gene.iter().map(|instr| Op::new(*instr)));
+        }
+        self.stale = false;
+    }
+
+    fn compile_gene(&mut self, gene_index: u8) {
+        for index in 0..self.cell.gene_size() {
+            self.compile_slot(gene_index, index as u8);
+        }
+    }
+
+    fn compile_slot(&mut self, gene_index: u8, index: u8) {
+        // wrap around like the cell does
+        let gene_index = gene_index % self.cell.gene_amount() as u8;
+        let index = index as usize % self.cell.gene_size();
+        let instr = self.cell.gene(gene_index)[index];
+        self.ops[gene_index as usize * self.cell.gene_size() + index] = Op::new(instr);
+    }
+
+    // Like Processor::execute.
+    pub fn execute(&mut self, processor: &mut Processor, amount: usize) {
+        // the config is checked once, see Processor::execute_traced
+        if amount == 0 || !processor.is_active() || !processor.check_config(&self.cell) {
+            return;
+        }
+        for _i in 0..amount {
+            if !processor.is_active() {
+                break;
+            }
+            self.step_checked(processor);
+        }
+    }
+
+    // Like Processor::step, without tracing.
+    pub fn step(&mut self, processor: &mut Processor) {
+        if processor.is_active() && processor.check_config(&self.cell) {
+            self.step_checked(processor);
+        }
+    }
+
+    fn step_checked(&mut self, processor: &mut Processor) {
+        if self.stale {
+            self.compile();
+        }
+        let (gene_index, pc) = processor.next_step();
+        let op = self.ops[gene_index as usize * self.cell.gene_size() + pc];
+        match op.writes {
+            Writes::Nothing => self.run(processor, op),
+            Writes::Slot => {
+                let gene_index = stack_value(processor, 3);
+                let index = stack_value(processor, 2);
+                self.run(processor, op);
+                self.compile_slot(gene_index, index);
+            }
+            Writes::Gene => {
+                let gene_index = stack_value(processor, 1);
+                self.run(processor, op);
+                self.compile_gene(gene_index);
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn run(&mut self, processor: &mut Processor, op: Op) {
+        (op.handler)(processor, &mut self.cell, op.argument);
+    }
+}
+
+// The value a pop at this depth is going to give; popping an empty stack
+// gives 0.
+fn stack_value(processor: &Processor, depth: usize) -> u8 {
+    let stack = processor.data_stack();
+    stack
+        .len()
+        .checked_sub(depth)
+        .map_or(0, |index| stack[index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::load;
+    use crate::data::{Addressing, StackPolicy, VmConfig};
+    use crate::encoding::decode_genes;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_handlers() {
+        for (opcode, instr) in OPCODES.iter().enumerate() {
+            assert_eq!(instr.opcode() as usize, opcode);
+        }
+        let mut c = Cell::new();
+        let mut p = Processor::new();
+        HANDLERS[0](&mut p, &mut c, 7);
+        HANDLERS[Instr::Dup.opcode() as usize](&mut p, &mut c, 0);
+        assert_eq!(p.data_stack(), &[7, 7]);
+    }
+
+    #[test]
+    fn test_write_to_running_gene() {
+        let mut c = Cell::new();
+        c.add_materials(10);
+        // overwrite the =5 at the end with a =3
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(0),
+                Instr::Number(6),
+                Instr::Number(Instr::Number(3).to_value()),
+                Instr::Write,
+                Instr::Noop,
+                Instr::Noop,
+                Instr::Number(5),
+            ],
+        );
+        let mut compiled = CompiledCell::new(c);
+        let mut p = Processor::new();
+        compiled.execute(&mut p, 7);
+        assert_eq!(p.data_stack(), &[3]);
+    }
+
+    #[test]
+    fn test_cell_mut() {
+        let mut compiled = CompiledCell::new(Cell::new());
+        compiled.cell_mut().set_gene(0, vec![Instr::Number(2)]);
+        let mut p = Processor::new();
+        compiled.step(&mut p);
+        assert_eq!(p.data_stack(), &[2]);
+    }
+
+    #[test]
+    fn test_other_config() {
+        let mut compiled = CompiledCell::new(Cell::new());
+        let mut p = Processor::with_config(VmConfig {
+            gene_size: 4,
+            ..VmConfig::default()
+        });
+        compiled.execute(&mut p, 10);
+        assert!(!p.is_active());
+        assert_eq!(p.faults(), 1);
+        assert_eq!(p.statistics().executed(), 0);
+    }
+
+    #[test]
+    fn test_stopped() {
+        let mut c = Cell::new();
+        c.add_materials(10);
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(0),
+                Instr::Number(0),
+                Instr::Number(9),
+                Instr::Write,
+            ],
+        );
+        let mut compiled = CompiledCell::new(c.clone());
+        let mut p = Processor::new();
+        p.stop();
+        let stopped = p.clone();
+        compiled.execute(&mut p, 100);
+        compiled.step(&mut p);
+        assert_eq!(p, stopped);
+        assert_eq!(compiled.cell(), &c);
+    }
+
+    #[test]
+    fn test_replicator_same_as_interpreter() {
+        let mut c = load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/genomes/replicator.txt"
+        ))
+        .unwrap();
+        c.add_materials(1000);
+        let mut compiled = CompiledCell::new(c.clone());
+        let mut p = Processor::new();
+        let mut compiled_p = Processor::new();
+        p.execute(&mut c, 20000);
+        compiled.execute(&mut compiled_p, 20000);
+        assert_eq!(p, compiled_p);
+        assert_eq!(&c, compiled.cell());
+    }
+
+    proptest! {
+        #[test]
+        fn test_same_as_interpreter(
+            bytes in prop::collection::vec(any::<u8>(), 0..600),
+            gene_size in 1..40usize,
+            gene_amount in 1..20usize,
+            templates in any::<bool>(),
+            policy in 0..4usize,
+            materials in 0..100u32,
+            seed in any::<u64>(),
+            gene_index in any::<u8>(),
+        ) {
+            let config = VmConfig {
+                gene_size,
+                gene_amount,
+                data_stack_size: 8,
+                call_stack_size: 4,
+                addressing: if templates {
+                    Addressing::Templates
+                } else {
+                    Addressing::Labels
+                },
+                stack_policy: [
+                    StackPolicy::Compress,
+                    StackPolicy::Wrap,
+                    StackPolicy::DropNewest,
+                    StackPolicy::Fault,
+                ][policy],
+                ..VmConfig::default()
+            };
+            let mut c = decode_genes(&bytes, OPCODES.len() as u8, config);
+            c.add_materials(materials);
+            let mut p = Processor::with_config(config);
+            p.set_seed(seed);
+            p.start(gene_index);
+            let mut compiled = CompiledCell::new(c.clone());
+            let mut compiled_p = p.clone();
+            p.execute(&mut c, 1000);
+            compiled.execute(&mut compiled_p, 1000);
+            prop_assert_eq!(&p, &compiled_p);
+            prop_assert_eq!(&c, compiled.cell());
+        }
+    }
+}
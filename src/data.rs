@@ -1,27 +1,51 @@
-use std::num::Wrapping;
-
-const GENE_SIZE: usize = 32;
-const GENE_AMOUNT: usize = 16;
-const PROCESSOR_AMOUNT: usize = 4;
-const LABEL_AMOUNT: usize = 4;
-const DATA_STACK_SIZE: usize = 32;
-const DATA_STACK_HALF_SIZE: usize = DATA_STACK_SIZE / 2;
-const INSTRUCTION_STACK_SIZE: usize = 32;
-const INSTRUCTION_STACK_HALF_SIZE: usize = INSTRUCTION_STACK_SIZE / 2;
-const CALL_STACK_SIZE: u8 = 32;
-const CALL_STACK_HALF_SIZE: u8 = CALL_STACK_SIZE / 2;
+use std::ops::Range;
 
+use crate::ports::PortQueue;
+use crate::statistics::Statistics;
+use crate::trace::{NoTracer, TraceRecord, Tracer};
+
+// The default dimensions of the VM, see VmConfig
+pub const GENE_SIZE: usize = 32;
+pub const GENE_AMOUNT: usize = 16;
+pub const LABEL_AMOUNT: usize = 4;
+pub const DATA_STACK_SIZE: usize = 32;
+pub const INSTRUCTION_STACK_SIZE: usize = 32;
+pub const CALL_STACK_SIZE: u8 = 32;
+
+pub const PROCESSOR_AMOUNT: usize = 4;
+pub const PORT_AMOUNT: usize = 8;
+pub const REGISTER_AMOUNT: usize = 8;
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Instr {
     Number(u8),
+    // Number(0) in a single instruction
+    Zero,
     // Nothing
     Noop,
+    // Nothing either, but they make up templates for template addressing
+    Nop0,
+    Nop1,
 
     // Arithmetic
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
+    Min,
+    Max,
+    Inc,
+    Dec,
+
+    // Bitwise
+    BitAnd,
+    BitOr,
+    BitXor,
+    // shift amounts wrap around at 8, like wrapping_shl
+    Shl,
+    Shr,
 
     // Comparison
     Eq,
@@ -41,6 +65,8 @@ pub enum Instr {
     Over,
     Dup2,
     Drop2,
+    // (a b c -- b c a)
+    Rot,
 
     // Calling genes
     Call,
@@ -50,49 +76,183 @@ pub enum Instr {
     Cond,
     Label,
     Jump,
+    // Jump within the gene, relative to the JumpRel itself or to an
+    // absolute pc. Both wrap around the gene.
+    JumpRel,
+    JumpAbs,
+    // SetLoop marks the start of a loop; Loop jumps back there if the top
+    // of the stack isn't 0
+    SetLoop,
+    Loop,
     // Read & write instructions
     // Writing costs materials, except for Noop instruction
-    // Read,
-    // Write,
+    Read,
+    Write,
+    // Registers shared by the processors of a cell. Instructions are
+    // executed one at a time, so TestAndSet is atomic: it sets a register
+    // to 1 and pushes what it was before.
+    Load,
+    Store,
+    TestAndSet,
 
     // Instruction stack inspection
     // Converts instruction stack entry to value stack and vice versa
-    // PopInstr,
-    // PushInstr,
-
+    PopInstr,
+    PushInstr,
+    // Writes the whole instruction stack into a gene
+    WriteGene,
     // Input and output gates to interact with world
     // these drive metabolism, where we're reading, where we're writing,
     // and sensors, and whether we're spawning a processor
     // Certain interactions induce others suppress
-    // In,
-    // Out,
+    SelectOut,
+    Out,
+    OutFull,
+    SelectIn,
+    In,
+    InEmpty,
+    // Randomness, deterministic given the seed of the processor
+    Rand,
+    RandRange,
+    // Interrupts: the gene to call on events, see Processor::interrupt
+    OnContact,
+    OnInput,
+    SetTimer,
+}
+
+// Something happening in the world that a processor can react to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    // something started touching this side of the cell
+    Contact(u8),
+    // this input port was empty and got a value
+    Input(u8),
+    // the timer set by SetTimer ran out
+    Timer,
+}
+
+// How Jump and Call find where to go.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Addressing {
+    // Jump goes to a label set by Label, Call takes a gene index from the
+    // stack
+    Labels,
+    // Like Tierra: Jump and Call are followed by a template of Nop0 and
+    // Nop1 instructions. Jump goes to the nearest complementary template in
+    // the gene, searching both ways; Call goes to the next gene that starts
+    // with the complementary template. Nothing happens if there is no such
+    // template.
+    Templates,
+}
+
+// What happens when pushing on a full stack or popping from an empty one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StackPolicy {
+    // a full stack loses its bottom half; popping an empty stack gives 0,
+    // or Noop for the instruction stack
+    Compress,
+    // a full stack loses its oldest value, like a ring buffer
+    Wrap,
+    // pushing on a full stack does nothing; for the call stack this means
+    // the call doesn't happen
+    DropNewest,
+    // like DropNewest, but both overflow and underflow stop the processor
+    // and count as a fault
+    Fault,
 }
 
+// The dimensions of cells and processors, and how they execute. Processors
+// should only run on cells with the same config.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VmConfig {
+    // at most 255, as the pc has to fit in a label
+    pub gene_size: usize,
+    // at most 255, as a gene index is a value
+    pub gene_amount: usize,
+    pub label_amount: usize,
+    pub data_stack_size: usize,
+    pub instruction_stack_size: usize,
+    pub call_stack_size: u8,
+    pub addressing: Addressing,
+    pub stack_policy: StackPolicy,
+}
+
+impl VmConfig {
+    pub fn is_valid(&self) -> bool {
+        (1..=255).contains(&self.gene_size)
+            && (1..=255).contains(&self.gene_amount)
+            && self.label_amount >= 1
+            && self.data_stack_size >= 1
+            && self.instruction_stack_size >= 1
+            && self.call_stack_size >= 1
+    }
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig {
+            gene_size: GENE_SIZE,
+            gene_amount: GENE_AMOUNT,
+            label_amount: LABEL_AMOUNT,
+            data_stack_size: DATA_STACK_SIZE,
+            instruction_stack_size: INSTRUCTION_STACK_SIZE,
+            call_stack_size: CALL_STACK_SIZE,
+            addressing: Addressing::Labels,
+            stack_policy: StackPolicy::Compress,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CallStackEntry {
     gene_index: u8,
     pc: usize,
-    labels: [u8; LABEL_AMOUNT],
+    loop_start: usize,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Processor {
+    config: VmConfig,
     active: bool,
+    // the gene the processor was started on, where it restarts
+    start_gene: u8,
     gene_index: u8,
     pc: usize,
-    labels: [u8; LABEL_AMOUNT],
+    // the labels of the callers and then of the current gene, label_amount
+    // each, so calls don't have to copy them
+    labels: Vec<u8>,
+    loop_start: usize,
     cond: bool,
     data_stack_index: usize,
     call_stack_index: u8,
     instruction_stack_index: usize,
-    data_stack: [u8; DATA_STACK_SIZE],
-    call_stack: [CallStackEntry; CALL_STACK_SIZE as usize],
-    instruction_stack: [Instr; INSTRUCTION_STACK_SIZE],
+    data_stack: Vec<u8>,
+    call_stack: Vec<CallStackEntry>,
+    instruction_stack: Vec<Instr>,
+    output_port: u8,
+    input_port: u8,
+    outputs: [PortQueue; PORT_AMOUNT],
+    inputs: [PortQueue; PORT_AMOUNT],
+    // state of the random number generator
+    rng: u64,
+    // stack errors under StackPolicy::Fault
+    faults: u32,
+    statistics: Statistics,
+    // the genes to call on events
+    contact_gene: Option<u8>,
+    input_gene: Option<u8>,
+    timer_gene: Option<u8>,
+    // ticks until the timer runs out, 0 if it's off
+    timer: u8,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Cell {
-    genes: [[Instr; GENE_SIZE]; GENE_AMOUNT],
+    config: VmConfig,
+    // all genes one after the other
+    genes: Vec<Instr>,
+    materials: u32,
+    registers: [u8; REGISTER_AMOUNT],
 }
 
 impl CallStackEntry {
@@ -100,12 +260,28 @@ impl CallStackEntry {
         CallStackEntry {
             gene_index: 0,
             pc: 0,
-            labels: [0; LABEL_AMOUNT],
+            loop_start: 0,
         }
     }
+
+    pub fn gene_index(&self) -> u8 {
+        self.gene_index
+    }
+
+    // where execution continues after the return
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn loop_start(&self) -> usize {
+        self.loop_start
+    }
 }
 
 impl Instr {
+    // Inlined so compiled genes get a version for each instruction, see
+    // compiled.rs
+    #[inline(always)]
     fn execute(&self, processor: &mut Processor) {
         if !processor.cond {
             processor.cond = true;
@@ -115,7 +291,10 @@ impl Instr {
             Instr::Number(n) => {
                 processor.data_push(n);
             }
-            Instr::Noop => {}
+            Instr::Zero => {
+                processor.data_push(0);
+            }
+            Instr::Noop | Instr::Nop0 | Instr::Nop1 => {}
             Instr::Add => {
                 let a = processor.data_pop();
                 let b = processor.data_pop();
@@ -140,6 +319,58 @@ impl Instr {
                     processor.data_push(b.wrapping_div(a));
                 }
             }
+            Instr::Mod => {
+                let a = processor.data_pop();
+                let b = processor.data_pop();
+                if a == 0 {
+                    processor.data_push(0);
+                } else {
+                    processor.data_push(b.wrapping_rem(a));
+                }
+            }
+            Instr::Min => {
+                let a = processor.data_pop();
+                let b = processor.data_pop();
+                processor.data_push(a.min(b));
+            }
+            Instr::Max => {
+                let a = processor.data_pop();
+                let b = processor.data_pop();
+                processor.data_push(a.max(b));
+            }
+            Instr::Inc => {
+                let a = processor.data_pop();
+                processor.data_push(a.wrapping_add(1));
+            }
+            Instr::Dec => {
+                let a = processor.data_pop();
+                processor.data_push(a.wrapping_sub(1));
+            }
+            Instr::BitAnd => {
+                let a = processor.data_pop();
+                let b = processor.data_pop();
+                processor.data_push(a & b);
+            }
+            Instr::BitOr => {
+                let a = processor.data_pop();
+                let b = processor.data_pop();
+                processor.data_push(a | b);
+            }
+            Instr::BitXor => {
+                let a = processor.data_pop();
+                let b = processor.data_pop();
+                processor.data_push(a ^ b);
+            }
+            Instr::Shl => {
+                let a = processor.data_pop();
+                let b = processor.data_pop();
+                processor.data_push(b.wrapping_shl(a as u32));
+            }
+            Instr::Shr => {
+                let a = processor.data_pop();
+                let b = processor.data_pop();
+                processor.data_push(b.wrapping_shr(a as u32));
+            }
             Instr::Eq => {
                 let a = processor.data_pop();
                 let b = processor.data_pop();
@@ -207,9 +438,17 @@ impl Instr {
                 processor.data_pop();
                 processor.data_pop();
             }
+            Instr::Rot => {
+                let c = processor.data_pop();
+                let b = processor.data_pop();
+                let a = processor.data_pop();
+                processor.data_push(b);
+                processor.data_push(c);
+                processor.data_push(a);
+            }
             Instr::Call => {
                 let gene_index = processor.data_pop();
-                processor.call_push(gene_index % (GENE_AMOUNT as u8));
+                processor.call_push(gene_index % (processor.config.gene_amount as u8));
             }
             Instr::Return => {
                 processor.call_pop();
@@ -220,67 +459,598 @@ impl Instr {
             }
             Instr::Label => {
                 let a = processor.data_pop();
-                processor.labels[(a as usize) % LABEL_AMOUNT] = processor.pc as u8;
+                let pc = processor.pc as u8;
+                let labels = processor.labels_mut();
+                let label_amount = labels.len();
+                labels[(a as usize) % label_amount] = pc;
             }
             Instr::Jump => {
                 let a = processor.data_pop();
-                processor.pc = processor.labels[(a as usize) % LABEL_AMOUNT] as usize;
+                let labels = processor.labels();
+                processor.pc = labels[(a as usize) % labels.len()] as usize;
+            }
+            Instr::JumpRel => {
+                let offset = processor.data_pop() as i8;
+                let gene_size = processor.config.gene_size as isize;
+                // pc is already past the JumpRel
+                let pc = processor.pc as isize - 1 + offset as isize;
+                processor.pc = pc.rem_euclid(gene_size) as usize;
+            }
+            Instr::JumpAbs => {
+                let a = processor.data_pop();
+                processor.pc = a as usize % processor.config.gene_size;
+            }
+            Instr::SetLoop => {
+                processor.loop_start = processor.pc;
+            }
+            Instr::Loop => {
+                let a = processor.data_pop();
+                if a != 0 {
+                    processor.pc = processor.loop_start;
+                }
+            }
+            Instr::PopInstr => {
+                let instr = processor.instruction_pop();
+                processor.data_push(instr.to_value());
+            }
+            Instr::PushInstr => {
+                let a = processor.data_pop();
+                processor.instruction_push(Instr::from_value(a));
+            }
+            Instr::SelectOut => {
+                let a = processor.data_pop();
+                processor.output_port = a % (PORT_AMOUNT as u8);
+            }
+            Instr::Out => {
+                let a = processor.data_pop();
+                // when the output is full the value is lost
+                processor.outputs[processor.output_port as usize].push(a);
+            }
+            Instr::OutFull => {
+                let full = processor.outputs[processor.output_port as usize].is_full();
+                processor.data_push(full as u8);
+            }
+            Instr::SelectIn => {
+                let a = processor.data_pop();
+                processor.input_port = a % (PORT_AMOUNT as u8);
+            }
+            Instr::In => {
+                let a = processor.inputs[processor.input_port as usize].pop();
+                processor.data_push(a.unwrap_or(0));
+            }
+            Instr::InEmpty => {
+                let empty = processor.inputs[processor.input_port as usize].is_empty();
+                processor.data_push(empty as u8);
+            }
+            Instr::Rand => {
+                let value = processor.random() as u8;
+                processor.data_push(value);
+            }
+            Instr::RandRange => {
+                let a = processor.data_pop();
+                // an empty range gives 0
+                let value = if a == 0 {
+                    0
+                } else {
+                    (processor.random() % a as u64) as u8
+                };
+                processor.data_push(value);
+            }
+            Instr::OnContact => {
+                let gene_index = processor.data_pop();
+                processor.contact_gene = Some(processor.wrap_gene_index(gene_index));
+            }
+            Instr::OnInput => {
+                let gene_index = processor.data_pop();
+                processor.input_gene = Some(processor.wrap_gene_index(gene_index));
+            }
+            Instr::SetTimer => {
+                // zero ticks turns the timer off
+                let ticks = processor.data_pop();
+                let gene_index = processor.data_pop();
+                processor.timer_gene = Some(processor.wrap_gene_index(gene_index));
+                processor.timer = ticks;
+            }
+            Instr::Read
+            | Instr::Write
+            | Instr::Load
+            | Instr::Store
+            | Instr::TestAndSet
+            | Instr::WriteGene => {
+                // these need the cell, see execute_in
+            }
+        }
+    }
+
+    // Execute instruction in the context of a cell.
+    #[inline(always)]
+    pub(crate) fn execute_in(&self, processor: &mut Processor, cell: &mut Cell) {
+        let templates = processor.config.addressing == Addressing::Templates;
+        match *self {
+            Instr::Jump if processor.cond && templates => {
+                let gene = cell.gene(processor.gene_index);
+                let start = processor.pc;
+                let end = template_end(gene, start);
+                // execution continues after the template if there is no
+                // match
+                processor.pc = find_template(gene, start, end).unwrap_or(end);
+            }
+            Instr::Call if processor.cond && templates => {
+                let start = processor.pc;
+                let end = template_end(cell.gene(processor.gene_index), start);
+                processor.pc = end;
+                if end == start {
+                    return;
+                }
+                let template = &cell.gene(processor.gene_index)[start..end];
+                // the next gene first, the calling gene itself last
+                let gene_amount = cell.gene_amount();
+                let found = (1..=gene_amount)
+                    .map(|offset| ((processor.gene_index as usize + offset) % gene_amount) as u8)
+                    .find(|gene_index| is_complement(template, cell.gene(*gene_index)));
+                if let Some(gene_index) = found {
+                    let length = template.len();
+                    if processor.call_push(gene_index) {
+                        processor.pc = length;
+                    }
+                }
+            }
+            Instr::Read if processor.cond => {
+                let index = processor.data_pop();
+                let gene_index = processor.data_pop();
+                let instr = cell.genes[cell.slot(gene_index, index)];
+                processor.data_push(instr.to_value());
+            }
+            Instr::Write if processor.cond => {
+                let value = processor.data_pop();
+                let index = processor.data_pop();
+                let gene_index = processor.data_pop();
+                let instr = Instr::from_value(value);
+                if instr != Instr::Noop {
+                    if cell.materials == 0 {
+                        return;
+                    }
+                    cell.materials -= 1;
+                }
+                let slot = cell.slot(gene_index, index);
+                cell.genes[slot] = instr;
+            }
+            Instr::Load if processor.cond => {
+                let index = processor.data_pop();
+                processor.data_push(cell.register(index));
+            }
+            Instr::Store if processor.cond => {
+                let index = processor.data_pop();
+                let value = processor.data_pop();
+                cell.set_register(index, value);
+            }
+            Instr::TestAndSet if processor.cond => {
+                let index = processor.data_pop();
+                processor.data_push(cell.register(index));
+                cell.set_register(index, 1);
+            }
+            Instr::WriteGene if processor.cond => {
+                let gene_index = processor.data_pop();
+                let start = cell.slot(gene_index, 0);
+                let gene = &mut cell.genes[start..start + cell.config.gene_size];
+                let amount = processor.instruction_stack_index;
+                // the bottom of the stack goes into the first slot, the
+                // rest of the gene is cleared
+                for (i, slot) in gene.iter_mut().enumerate() {
+                    let instr = if i < amount {
+                        processor.instruction_stack[i]
+                    } else {
+                        Instr::Noop
+                    };
+                    if instr != Instr::Noop {
+                        // like Write, skip instructions we have no materials for
+                        if cell.materials == 0 {
+                            continue;
+                        }
+                        cell.materials -= 1;
+                    }
+                    *slot = instr;
+                }
+                processor.instruction_stack_index = 0;
+            }
+            _ => self.execute(processor),
+        }
+    }
+}
+
+// The end of the template of Nop0 and Nop1 that starts at start.
+fn template_end(gene: &[Instr], start: usize) -> usize {
+    gene[start.min(gene.len())..]
+        .iter()
+        .position(|instr| !matches!(instr, Instr::Nop0 | Instr::Nop1))
+        .map_or(gene.len(), |length| start + length)
+}
+
+// Does the start of instructions complement the template?
+fn is_complement(template: &[Instr], instructions: &[Instr]) -> bool {
+    template.len() <= instructions.len()
+        && template.iter().zip(instructions).all(|pair| {
+            matches!(
+                pair,
+                (Instr::Nop0, Instr::Nop1) | (Instr::Nop1, Instr::Nop0)
+            )
+        })
+}
+
+// The pc after the complementary template nearest to the template between
+// start and end, or None if there is no template or no complement.
+fn find_template(gene: &[Instr], start: usize, end: usize) -> Option<usize> {
+    let template = &gene[start..end];
+    if template.is_empty() {
+        return None;
+    }
+    let length = template.len();
+    // the instruction before the template is the jump itself
+    let jump = start - 1;
+    for distance in 1..gene.len() {
+        // forward, past the template
+        let forward = end + distance - 1;
+        if forward + length <= gene.len() && is_complement(template, &gene[forward..]) {
+            return Some(forward + length);
+        }
+        // backward, ending before the jump
+        if distance + length <= jump + 1 {
+            let backward = jump + 1 - distance - length;
+            if is_complement(template, &gene[backward..]) {
+                return Some(backward + length);
             }
         }
     }
+    None
 }
+
 impl Processor {
     pub fn new() -> Processor {
+        Processor::with_config(VmConfig::default())
+    }
+
+    pub fn with_config(config: VmConfig) -> Processor {
+        assert!(config.is_valid(), "invalid VM config {:?}", config);
         Processor {
+            config,
             active: true,
+            start_gene: 0,
             gene_index: 0,
             pc: 0,
-            labels: [0; LABEL_AMOUNT],
+            labels: vec![0; (config.call_stack_size as usize + 1) * config.label_amount],
+            loop_start: 0,
             cond: true,
             data_stack_index: 0,
             call_stack_index: 0,
             instruction_stack_index: 0,
-            data_stack: [0; DATA_STACK_SIZE],
-            call_stack: [CallStackEntry::new(); CALL_STACK_SIZE as usize],
-            instruction_stack: [Instr::Noop; INSTRUCTION_STACK_SIZE],
+            data_stack: vec![0; config.data_stack_size],
+            call_stack: vec![CallStackEntry::new(); config.call_stack_size as usize],
+            instruction_stack: vec![Instr::Noop; config.instruction_stack_size],
+            output_port: 0,
+            input_port: 0,
+            outputs: [PortQueue::new(); PORT_AMOUNT],
+            inputs: [PortQueue::new(); PORT_AMOUNT],
+            rng: 0,
+            faults: 0,
+            statistics: Statistics::new(),
+            contact_gene: None,
+            input_gene: None,
+            timer_gene: None,
+            timer: 0,
         }
     }
 
+    // Processors with the same seed draw the same random numbers. Restarting
+    // a processor doesn't reset its random number generator.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = seed;
+    }
+
+    // splitmix64; small state and good enough for genomes
+    fn random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix(self.rng)
+    }
+
     fn reset(&mut self) {
-        self.gene_index = 0;
+        self.gene_index = self.start_gene;
         self.pc = 0;
-        self.labels = [0; LABEL_AMOUNT];
+        self.labels[..self.config.label_amount].fill(0);
+        self.loop_start = 0;
         self.cond = true;
         self.data_stack_index = 0;
         self.call_stack_index = 0;
         self.instruction_stack_index = 0;
+        // the queues are left alone, they belong to the world too
+        self.output_port = 0;
+        self.input_port = 0;
+    }
+
+    // Unlike a restart after returning from the main gene, starting a
+    // processor forgets its interrupts.
+    pub fn start(&mut self, gene_index: u8) {
+        self.reset();
+        self.contact_gene = None;
+        self.input_gene = None;
+        self.timer_gene = None;
+        self.timer = 0;
+        self.start_gene = self.wrap_gene_index(gene_index);
+        self.gene_index = self.start_gene;
+        self.active = true;
+    }
+
+    pub fn config(&self) -> VmConfig {
+        self.config
+    }
+
+    pub fn stop(&mut self) {
+        self.active = false;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    // How often the processor faulted. Restarting the processor doesn't
+    // reset this.
+    pub fn faults(&self) -> u32 {
+        self.faults
+    }
+
+    // What the processor did since the statistics were last reset.
+    // Restarting the processor doesn't reset them.
+    pub fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+
+    pub fn reset_statistics(&mut self) {
+        self.statistics = Statistics::new();
+    }
+
+    fn wrap_gene_index(&self, gene_index: u8) -> u8 {
+        gene_index % (self.config.gene_amount as u8)
+    }
+
+    // Call the gene registered for the event, as if the next instruction
+    // was a Call. The event data, if any, goes on the data stack. A false
+    // cond is dropped, otherwise the first instruction of the gene would
+    // be skipped. Returns false if nothing was called.
+    pub fn interrupt(&mut self, event: Event) -> bool {
+        if !self.active {
+            return false;
+        }
+        let (gene_index, data) = match event {
+            Event::Contact(side) => (self.contact_gene, Some(side)),
+            Event::Input(port) => (self.input_gene, Some(port)),
+            Event::Timer => (self.timer_gene, None),
+        };
+        let gene_index = match gene_index {
+            Some(gene_index) => gene_index,
+            None => return false,
+        };
+        if !self.call_push(gene_index) {
+            return false;
+        }
+        self.cond = true;
+        if let Some(value) = data {
+            self.data_push(value);
+        }
+        true
+    }
+
+    // Count down the timer, interrupting when it runs out. The world does
+    // this once per tick.
+    pub fn tick_timer(&mut self) {
+        if self.timer == 0 {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.interrupt(Event::Timer);
+        }
+    }
+
+    // The ticks until the timer runs out, 0 if it's off.
+    pub fn timer(&self) -> u8 {
+        self.timer
+    }
+
+    pub fn gene_index(&self) -> u8 {
+        self.gene_index
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn cond(&self) -> bool {
+        self.cond
+    }
+
+    pub fn labels(&self) -> &[u8] {
+        self.labels_at(self.call_stack_index as usize)
+    }
+
+    fn labels_mut(&mut self) -> &mut [u8] {
+        let label_amount = self.config.label_amount;
+        let start = self.call_stack_index as usize * label_amount;
+        &mut self.labels[start..start + label_amount]
+    }
+
+    // The labels of the caller at this depth of the call stack, the
+    // bottom being 0.
+    pub fn labels_at(&self, depth: usize) -> &[u8] {
+        let label_amount = self.config.label_amount;
+        let start = depth * label_amount;
+        &self.labels[start..start + label_amount]
+    }
+
+    pub fn loop_start(&self) -> usize {
+        self.loop_start
+    }
+
+    // The values on the data stack, the top last.
+    pub fn data_stack(&self) -> &[u8] {
+        &self.data_stack[..self.data_stack_index]
+    }
+
+    // The callers, the most recent last.
+    pub fn call_stack(&self) -> &[CallStackEntry] {
+        &self.call_stack[..self.call_stack_index as usize]
+    }
+
+    pub fn instruction_stack(&self) -> &[Instr] {
+        &self.instruction_stack[..self.instruction_stack_index]
+    }
+
+    pub fn output_mut(&mut self, port: u8) -> &mut PortQueue {
+        &mut self.outputs[(port as usize) % PORT_AMOUNT]
+    }
+
+    pub fn input_mut(&mut self, port: u8) -> &mut PortQueue {
+        &mut self.inputs[(port as usize) % PORT_AMOUNT]
+    }
+
+    pub fn execute(&mut self, cell: &mut Cell, amount: usize) {
+        self.execute_traced(cell, amount, &mut NoTracer);
     }
 
-    fn execute(&mut self, cell: &Cell, amount: usize) {
+    // Like execute, but the tracer sees every executed instruction.
+    // Execution ends early if the processor faults.
+    pub fn execute_traced<T: Tracer>(&mut self, cell: &mut Cell, amount: usize, tracer: &mut T) {
+        // configs don't change, so checking once is enough
+        if amount == 0 || !self.active || !self.check_config(cell) {
+            return;
+        }
         for _i in 0..amount {
-            let instruction;
-            // update pc to next pc; may be overwritten by instruction
-            if self.pc < GENE_SIZE {
-                // fetch instruction first
-                instruction = cell.genes[self.gene_index as usize][self.pc];
-                self.pc += 1;
+            if !self.active {
+                break;
+            }
+            self.step_checked(cell, tracer);
+        }
+    }
+
+    // Execute a single instruction, returning it.
+    pub fn step(&mut self, cell: &mut Cell) -> Instr {
+        self.step_traced(cell, &mut NoTracer)
+    }
+
+    // A stopped processor doesn't execute anything, and the step gives
+    // Noop. A processor only runs on cells with the same config; on any
+    // other cell it faults without executing anything too.
+    pub fn step_traced<T: Tracer>(&mut self, cell: &mut Cell, tracer: &mut T) -> Instr {
+        if !self.active || !self.check_config(cell) {
+            return Instr::Noop;
+        }
+        self.step_checked(cell, tracer)
+    }
+
+    // A step on a cell with the same config.
+    fn step_checked<T: Tracer>(&mut self, cell: &mut Cell, tracer: &mut T) -> Instr {
+        let (gene_index, pc) = self.next_step();
+        let depth_before = self.data_stack_index;
+        let call_depth = self.call_stack_index as usize;
+        let skipped = !self.cond;
+        // fetch instruction first
+        let instruction = cell.gene(gene_index)[pc];
+        self.advance(instruction.opcode());
+        // now execute instruction
+        instruction.execute_in(self, cell);
+        tracer.trace(&TraceRecord {
+            gene_index,
+            pc,
+            instr: instruction,
+            skipped,
+            depth_before,
+            depth_after: self.data_stack_index,
+            call_depth,
+        });
+        instruction
+    }
+
+    // Where the instruction of the next step is. At the end of the gene we
+    // try a return; we can return to the end of the calling gene too, so we
+    // may have to return again.
+    pub(crate) fn next_step(&mut self) -> (u8, usize) {
+        while self.pc >= self.config.gene_size {
+            self.call_pop();
+        }
+        (self.gene_index, self.pc)
+    }
+
+    // Count the instruction at the pc and move past it. The instruction may
+    // overwrite the pc after this.
+    pub(crate) fn advance(&mut self, opcode: u8) {
+        self.statistics.record_step(opcode, !self.cond);
+        self.pc += 1;
+    }
+
+    // The instruction the next step is going to execute.
+    pub fn next_instruction(&self, cell: &Cell) -> Instr {
+        // a stopped processor doesn't step, and on a cell with another
+        // config the step faults instead
+        if !self.active || self.config != cell.config {
+            return Instr::Noop;
+        }
+        let (gene_index, pc) = self.next_location();
+        cell.gene(gene_index)[pc]
+    }
+
+    // The gene index and pc of the instruction the next step is going to
+    // execute.
+    pub fn next_location(&self) -> (u8, usize) {
+        let mut gene_index = self.gene_index;
+        let mut pc = self.pc;
+        let mut call_stack_index = self.call_stack_index;
+        // follow the returns step is going to do
+        while pc >= self.config.gene_size {
+            if call_stack_index == 0 {
+                // returning from the main gene starts it again
+                gene_index = self.start_gene;
+                pc = 0;
             } else {
-                // otherwise we try a return
-                self.call_pop();
-                instruction = cell.genes[self.gene_index as usize][self.pc];
-                self.pc += 1;
+                call_stack_index -= 1;
+                let entry = &self.call_stack[call_stack_index as usize];
+                gene_index = entry.gene_index;
+                pc = entry.pc;
             }
-            // now execute instruction
-            instruction.execute(self);
+        }
+        (gene_index, pc)
+    }
+
+    // Faults if the cell has another config than the processor.
+    pub(crate) fn check_config(&mut self, cell: &Cell) -> bool {
+        if self.config != cell.config {
+            self.fault();
+            return false;
+        }
+        true
+    }
+
+    fn fault(&mut self) {
+        // a stopped processor can't fault again
+        if self.active {
+            self.faults += 1;
+        }
+        self.active = false;
+    }
+
+    // A push or pop the stack policy doesn't allow.
+    fn stack_error(&mut self) {
+        if self.config.stack_policy == StackPolicy::Fault {
+            self.fault();
         }
     }
 
     fn data_push(&mut self, value: u8) {
-        // compress stack if needed
-        if self.data_stack_index >= DATA_STACK_SIZE {
-            self.data_stack_index = DATA_STACK_HALF_SIZE;
-            for i in 0..DATA_STACK_HALF_SIZE {
-                self.data_stack[i] = self.data_stack[i + DATA_STACK_HALF_SIZE];
+        if self.data_stack_index >= self.data_stack.len() {
+            self.statistics.record_overflow();
+            match make_room(&mut self.data_stack, self.config.stack_policy) {
+                Some(depth) => self.data_stack_index = depth,
+                None => {
+                    self.stack_error();
+                    return;
+                }
             }
         }
         self.data_stack[self.data_stack_index] = value;
@@ -289,6 +1059,8 @@ impl Processor {
 
     fn data_pop(&mut self) -> u8 {
         if self.data_stack_index == 0 {
+            self.statistics.record_underflow();
+            self.stack_error();
             0
         } else {
             self.data_stack_index -= 1;
@@ -296,71 +1068,350 @@ impl Processor {
         }
     }
 
-    fn call_push(&mut self, gene_index: u8) {
-        // compress stack if needed
-        if self.call_stack_index >= CALL_STACK_SIZE {
-            self.call_stack_index = CALL_STACK_HALF_SIZE;
-            for i in 0..CALL_STACK_HALF_SIZE {
-                self.call_stack[i as usize] = self.call_stack[(i + CALL_STACK_HALF_SIZE) as usize];
+    fn instruction_push(&mut self, instr: Instr) {
+        if self.instruction_stack_index >= self.instruction_stack.len() {
+            self.statistics.record_overflow();
+            match make_room(&mut self.instruction_stack, self.config.stack_policy) {
+                Some(depth) => self.instruction_stack_index = depth,
+                None => {
+                    self.stack_error();
+                    return;
+                }
             }
         }
-        self.call_stack[self.call_stack_index as usize] = CallStackEntry {
-            gene_index: self.gene_index,
-            pc: self.pc,
-            labels: self.labels,
-        };
+        self.instruction_stack[self.instruction_stack_index] = instr;
+        self.instruction_stack_index += 1;
+    }
+
+    fn instruction_pop(&mut self) -> Instr {
+        if self.instruction_stack_index == 0 {
+            self.statistics.record_underflow();
+            self.stack_error();
+            Instr::Noop
+        } else {
+            self.instruction_stack_index -= 1;
+            self.instruction_stack[self.instruction_stack_index]
+        }
+    }
+
+    // Returns false if the stack policy doesn't allow the call.
+    fn call_push(&mut self, gene_index: u8) -> bool {
+        if self.call_stack_index as usize >= self.call_stack.len() {
+            self.statistics.record_overflow();
+            let size = self.call_stack.len();
+            match make_room(&mut self.call_stack, self.config.stack_policy) {
+                Some(depth) => {
+                    // the labels of the current gene move along with the
+                    // callers that are left
+                    let label_amount = self.config.label_amount;
+                    self.labels.rotate_left((size - depth) * label_amount);
+                    self.call_stack_index = depth as u8;
+                }
+                None => {
+                    self.stack_error();
+                    return false;
+                }
+            }
+        }
+        let entry = &mut self.call_stack[self.call_stack_index as usize];
+        entry.gene_index = self.gene_index;
+        entry.pc = self.pc;
+        entry.loop_start = self.loop_start;
         self.call_stack_index += 1;
         self.pc = 0;
         self.gene_index = gene_index;
-        self.labels = [0; LABEL_AMOUNT];
+        self.labels_mut().fill(0);
+        self.loop_start = 0;
+        true
     }
 
     fn call_pop(&mut self) {
         if self.call_stack_index == 0 {
             // restart, all anew
+            self.statistics.record_call_reset();
             self.reset();
             return;
         }
         self.call_stack_index -= 1;
-        let entry = self.call_stack[self.call_stack_index as usize];
+        let entry = &self.call_stack[self.call_stack_index as usize];
         self.gene_index = entry.gene_index;
         self.pc = entry.pc;
-        self.labels = entry.labels;
+        self.loop_start = entry.loop_start;
     }
 }
 
-impl Cell {
-    pub fn new() -> Cell {
-        Cell {
-            genes: [[Instr::Noop; GENE_SIZE]; GENE_AMOUNT],
-        }
-    }
+// The output function of splitmix64. Different inputs give different
+// outputs.
+pub(crate) fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
 
-    fn set_gene(&mut self, gene_index: u8, instructions: Vec<Instr>) {
-        if instructions.len() > GENE_SIZE {
-            panic!("More instructions than fit!");
-        }
-        let gene = &mut self.genes[gene_index as usize];
-        for i in 0..instructions.len() {
-            gene[i] = instructions[i];
-        }
-        for i in instructions.len()..GENE_SIZE {
-            gene[i] = Instr::Noop;
-        }
+// Makes room on a full stack by rotating out its oldest entries, returning
+// the new depth, or None if the stack policy doesn't allow the push.
+fn make_room<T>(stack: &mut [T], policy: StackPolicy) -> Option<usize> {
+    let size = stack.len();
+    let depth = match policy {
+        // keep the top half
+        StackPolicy::Compress => size / 2,
+        StackPolicy::Wrap => size - 1,
+        StackPolicy::DropNewest | StackPolicy::Fault => return None,
+    };
+    stack.rotate_left(size - depth);
+    Some(depth)
+}
+
+impl Default for Processor {
+    fn default() -> Processor {
+        Processor::new()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// The state of many processors with the same config as columns: one array
+// per field, and the stacks and labels one after the other in a single
+// array each. Batches run processors from these, see batch.rs.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProcessorColumns {
+    actives: Vec<bool>,
+    start_genes: Vec<u8>,
+    gene_indices: Vec<u8>,
+    pcs: Vec<usize>,
+    labels: Vec<u8>,
+    loop_starts: Vec<usize>,
+    conds: Vec<bool>,
+    data_stack_lens: Vec<usize>,
+    call_stack_lens: Vec<u8>,
+    instruction_stack_lens: Vec<usize>,
+    data_stacks: Vec<u8>,
+    call_stacks: Vec<CallStackEntry>,
+    instruction_stacks: Vec<Instr>,
+    output_ports: Vec<u8>,
+    input_ports: Vec<u8>,
+    outputs: Vec<[PortQueue; PORT_AMOUNT]>,
+    inputs: Vec<[PortQueue; PORT_AMOUNT]>,
+    rngs: Vec<u64>,
+    faults: Vec<u32>,
+    statistics: Vec<Statistics>,
+    contact_genes: Vec<Option<u8>>,
+    input_genes: Vec<Option<u8>>,
+    timer_genes: Vec<Option<u8>>,
+    timers: Vec<u8>,
+}
 
-    #[test]
-    fn test_data_stack() {
-        let mut p = Processor::new();
-        p.data_push(1);
-        assert_eq!(p.data_stack_index, 1);
-        p.data_push(2);
-        assert_eq!(p.data_stack_index, 2);
+impl ProcessorColumns {
+    pub(crate) fn len(&self) -> usize {
+        self.pcs.len()
+    }
+
+    pub(crate) fn is_active(&self, index: usize) -> bool {
+        self.actives[index]
+    }
+
+    pub(crate) fn push(&mut self, p: &Processor) {
+        self.actives.push(p.active);
+        self.start_genes.push(p.start_gene);
+        self.gene_indices.push(p.gene_index);
+        self.pcs.push(p.pc);
+        self.labels.extend_from_slice(&p.labels);
+        self.loop_starts.push(p.loop_start);
+        self.conds.push(p.cond);
+        self.data_stack_lens.push(p.data_stack_index);
+        self.call_stack_lens.push(p.call_stack_index);
+        self.instruction_stack_lens.push(p.instruction_stack_index);
+        self.data_stacks.extend_from_slice(&p.data_stack);
+        self.call_stacks.extend_from_slice(&p.call_stack);
+        self.instruction_stacks
+            .extend_from_slice(&p.instruction_stack);
+        self.output_ports.push(p.output_port);
+        self.input_ports.push(p.input_port);
+        self.outputs.push(p.outputs);
+        self.inputs.push(p.inputs);
+        self.rngs.push(p.rng);
+        self.faults.push(p.faults);
+        self.statistics.push(p.statistics);
+        self.contact_genes.push(p.contact_gene);
+        self.input_genes.push(p.input_gene);
+        self.timer_genes.push(p.timer_gene);
+        self.timers.push(p.timer);
+    }
+
+    // Copies the processor at the index into p, which has to have the
+    // config of the columns.
+    pub(crate) fn load(&self, index: usize, p: &mut Processor) {
+        let labels = span(index, p.labels.len());
+        let data_stack = span(index, p.data_stack.len());
+        let call_stack = span(index, p.call_stack.len());
+        let instruction_stack = span(index, p.instruction_stack.len());
+        p.active = self.actives[index];
+        p.start_gene = self.start_genes[index];
+        p.gene_index = self.gene_indices[index];
+        p.pc = self.pcs[index];
+        p.labels.copy_from_slice(&self.labels[labels]);
+        p.loop_start = self.loop_starts[index];
+        p.cond = self.conds[index];
+        p.data_stack_index = self.data_stack_lens[index];
+        p.call_stack_index = self.call_stack_lens[index];
+        p.instruction_stack_index = self.instruction_stack_lens[index];
+        p.data_stack.copy_from_slice(&self.data_stacks[data_stack]);
+        p.call_stack.clone_from_slice(&self.call_stacks[call_stack]);
+        p.instruction_stack
+            .copy_from_slice(&self.instruction_stacks[instruction_stack]);
+        p.output_port = self.output_ports[index];
+        p.input_port = self.input_ports[index];
+        p.outputs = self.outputs[index];
+        p.inputs = self.inputs[index];
+        p.rng = self.rngs[index];
+        p.faults = self.faults[index];
+        p.statistics = self.statistics[index];
+        p.contact_gene = self.contact_genes[index];
+        p.input_gene = self.input_genes[index];
+        p.timer_gene = self.timer_genes[index];
+        p.timer = self.timers[index];
+    }
+
+    // Copies p back to the index, the opposite of load.
+    pub(crate) fn store(&mut self, index: usize, p: &Processor) {
+        self.actives[index] = p.active;
+        self.start_genes[index] = p.start_gene;
+        self.gene_indices[index] = p.gene_index;
+        self.pcs[index] = p.pc;
+        self.labels[span(index, p.labels.len())].copy_from_slice(&p.labels);
+        self.loop_starts[index] = p.loop_start;
+        self.conds[index] = p.cond;
+        self.data_stack_lens[index] = p.data_stack_index;
+        self.call_stack_lens[index] = p.call_stack_index;
+        self.instruction_stack_lens[index] = p.instruction_stack_index;
+        self.data_stacks[span(index, p.data_stack.len())].copy_from_slice(&p.data_stack);
+        self.call_stacks[span(index, p.call_stack.len())].clone_from_slice(&p.call_stack);
+        self.instruction_stacks[span(index, p.instruction_stack.len())]
+            .copy_from_slice(&p.instruction_stack);
+        self.output_ports[index] = p.output_port;
+        self.input_ports[index] = p.input_port;
+        self.outputs[index] = p.outputs;
+        self.inputs[index] = p.inputs;
+        self.rngs[index] = p.rng;
+        self.faults[index] = p.faults;
+        self.statistics[index] = p.statistics;
+        self.contact_genes[index] = p.contact_gene;
+        self.input_genes[index] = p.input_gene;
+        self.timer_genes[index] = p.timer_gene;
+        self.timers[index] = p.timer;
+    }
+}
+
+// Where the part of the processor at the index is in an array that holds
+// size values for each processor.
+fn span(index: usize, size: usize) -> Range<usize> {
+    index * size..(index + 1) * size
+}
+
+impl Cell {
+    pub fn new() -> Cell {
+        Cell::with_config(VmConfig::default())
+    }
+
+    pub fn with_config(config: VmConfig) -> Cell {
+        assert!(config.is_valid(), "invalid VM config {:?}", config);
+        Cell {
+            config,
+            genes: vec![Instr::Noop; config.gene_size * config.gene_amount],
+            materials: 0,
+            registers: [0; REGISTER_AMOUNT],
+        }
+    }
+
+    pub fn config(&self) -> VmConfig {
+        self.config
+    }
+
+    pub fn gene_size(&self) -> usize {
+        self.config.gene_size
+    }
+
+    pub fn gene_amount(&self) -> usize {
+        self.config.gene_amount
+    }
+
+    // Where an instruction is in genes. Gene indexes and indexes that are
+    // out of range wrap around.
+    fn slot(&self, gene_index: u8, index: u8) -> usize {
+        (gene_index as usize % self.config.gene_amount) * self.config.gene_size
+            + (index as usize % self.config.gene_size)
+    }
+
+    pub fn materials(&self) -> u32 {
+        self.materials
+    }
+
+    pub fn add_materials(&mut self, amount: u32) {
+        self.materials = self.materials.saturating_add(amount);
+    }
+
+    // Register indexes wrap around.
+    pub fn register(&self, index: u8) -> u8 {
+        self.registers[index as usize % REGISTER_AMOUNT]
+    }
+
+    pub fn set_register(&mut self, index: u8, value: u8) {
+        self.registers[index as usize % REGISTER_AMOUNT] = value;
+    }
+
+    // Gene indexes wrap around, like in slot.
+    pub fn gene(&self, gene_index: u8) -> &[Instr] {
+        let start = self.slot(gene_index, 0);
+        &self.genes[start..start + self.config.gene_size]
+    }
+
+    pub fn gene_mut(&mut self, gene_index: u8) -> &mut [Instr] {
+        let start = self.slot(gene_index, 0);
+        &mut self.genes[start..start + self.config.gene_size]
+    }
+
+    // Instructions that don't fit in the gene are dropped.
+    pub fn set_gene(&mut self, gene_index: u8, mut instructions: Vec<Instr>) {
+        instructions.truncate(self.config.gene_size);
+        let gene = self.gene_mut(gene_index);
+        gene[..instructions.len()].copy_from_slice(&instructions);
+        gene[instructions.len()..].fill(Instr::Noop);
+    }
+}
+
+impl Default for Cell {
+    fn default() -> Cell {
+        Cell::new()
+    }
+}
+
+// The length of the instructions without the Noops at the end. Genes are
+// filled up with Noop, so these Noops are implied when a genome is saved
+// or shown.
+pub fn trimmed_length(instructions: &[Instr]) -> usize {
+    instructions
+        .iter()
+        .rposition(|instr| *instr != Instr::Noop)
+        .map_or(0, |position| position + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::load;
+    use crate::encoding::{decode_genes, OPCODES};
+    use crate::ports::PORT_QUEUE_SIZE;
+    use proptest::prelude::*;
+
+    const DATA_STACK_HALF_SIZE: usize = DATA_STACK_SIZE / 2;
+    const INSTRUCTION_STACK_HALF_SIZE: usize = INSTRUCTION_STACK_SIZE / 2;
+    const CALL_STACK_HALF_SIZE: u8 = CALL_STACK_SIZE / 2;
+
+    #[test]
+    fn test_data_stack() {
+        let mut p = Processor::new();
+        p.data_push(1);
+        assert_eq!(p.data_stack_index, 1);
+        p.data_push(2);
+        assert_eq!(p.data_stack_index, 2);
         assert_eq!(p.data_pop(), 2);
         assert_eq!(p.data_pop(), 1);
         // stack underflow
@@ -381,6 +1432,36 @@ mod tests {
         assert_eq!(p.data_pop(), DATA_STACK_SIZE as u8 - 1)
     }
 
+    #[test]
+    fn test_instruction_stack() {
+        let mut p = Processor::new();
+        p.instruction_push(Instr::Add);
+        assert_eq!(p.instruction_stack_index, 1);
+        p.instruction_push(Instr::Number(3));
+        assert_eq!(p.instruction_stack_index, 2);
+        assert_eq!(p.instruction_pop(), Instr::Number(3));
+        assert_eq!(p.instruction_pop(), Instr::Add);
+        // stack underflow
+        assert_eq!(p.instruction_pop(), Instr::Noop);
+    }
+
+    #[test]
+    fn test_instruction_stack_overflow() {
+        let mut p = Processor::new();
+        for i in 0..INSTRUCTION_STACK_SIZE {
+            p.instruction_push(Instr::Number(i as u8));
+        }
+        assert_eq!(p.instruction_stack_index, INSTRUCTION_STACK_SIZE);
+        // now smash the stack
+        p.instruction_push(Instr::Add);
+        assert_eq!(p.instruction_stack_index, INSTRUCTION_STACK_HALF_SIZE + 1);
+        assert_eq!(p.instruction_pop(), Instr::Add);
+        assert_eq!(
+            p.instruction_pop(),
+            Instr::Number(INSTRUCTION_STACK_SIZE as u8 - 1)
+        )
+    }
+
     #[test]
     fn test_instr_number() {
         let mut p = Processor::new();
@@ -459,6 +1540,176 @@ mod tests {
         assert_eq!(p.data_pop(), 0);
     }
 
+    #[test]
+    fn test_instr_mod() {
+        let mut p = Processor::new();
+        Instr::Number(7).execute(&mut p);
+        Instr::Number(3).execute(&mut p);
+        Instr::Mod.execute(&mut p);
+        assert_eq!(p.data_pop(), 1);
+    }
+
+    #[test]
+    fn test_instr_mod_zero() {
+        let mut p = Processor::new();
+        Instr::Number(7).execute(&mut p);
+        Instr::Number(0).execute(&mut p);
+        Instr::Mod.execute(&mut p);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_instr_min() {
+        let mut p = Processor::new();
+        Instr::Number(7).execute(&mut p);
+        Instr::Number(3).execute(&mut p);
+        Instr::Min.execute(&mut p);
+        assert_eq!(p.data_pop(), 3);
+    }
+
+    #[test]
+    fn test_instr_max() {
+        let mut p = Processor::new();
+        Instr::Number(7).execute(&mut p);
+        Instr::Number(3).execute(&mut p);
+        Instr::Max.execute(&mut p);
+        assert_eq!(p.data_pop(), 7);
+    }
+
+    #[test]
+    fn test_instr_inc() {
+        let mut p = Processor::new();
+        Instr::Number(6).execute(&mut p);
+        Instr::Inc.execute(&mut p);
+        assert_eq!(p.data_pop(), 7);
+    }
+
+    #[test]
+    fn test_instr_inc_overflow() {
+        let mut p = Processor::new();
+        Instr::Number(255).execute(&mut p);
+        Instr::Inc.execute(&mut p);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_instr_dec() {
+        let mut p = Processor::new();
+        Instr::Number(6).execute(&mut p);
+        Instr::Dec.execute(&mut p);
+        assert_eq!(p.data_pop(), 5);
+    }
+
+    #[test]
+    fn test_instr_dec_underflow() {
+        let mut p = Processor::new();
+        Instr::Number(0).execute(&mut p);
+        Instr::Dec.execute(&mut p);
+        assert_eq!(p.data_pop(), 255);
+    }
+
+    #[test]
+    fn test_instr_bit_and() {
+        let mut p = Processor::new();
+        Instr::Number(12).execute(&mut p);
+        Instr::Number(10).execute(&mut p);
+        Instr::BitAnd.execute(&mut p);
+        assert_eq!(p.data_pop(), 0b1000);
+    }
+
+    #[test]
+    fn test_instr_bit_or() {
+        let mut p = Processor::new();
+        Instr::Number(12).execute(&mut p);
+        Instr::Number(10).execute(&mut p);
+        Instr::BitOr.execute(&mut p);
+        assert_eq!(p.data_pop(), 0b1110);
+    }
+
+    #[test]
+    fn test_instr_bit_xor() {
+        let mut p = Processor::new();
+        Instr::Number(12).execute(&mut p);
+        Instr::Number(10).execute(&mut p);
+        Instr::BitXor.execute(&mut p);
+        assert_eq!(p.data_pop(), 0b0110);
+    }
+
+    #[test]
+    fn test_instr_shl() {
+        let mut p = Processor::new();
+        Instr::Number(3).execute(&mut p);
+        Instr::Number(2).execute(&mut p);
+        Instr::Shl.execute(&mut p);
+        assert_eq!(p.data_pop(), 12);
+    }
+
+    #[test]
+    fn test_instr_shl_overflow() {
+        let mut p = Processor::new();
+        Instr::Number(129).execute(&mut p);
+        Instr::Number(1).execute(&mut p);
+        Instr::Shl.execute(&mut p);
+        assert_eq!(p.data_pop(), 2);
+    }
+
+    #[test]
+    fn test_instr_shl_wraps_amount() {
+        let mut p = Processor::new();
+        Instr::Number(3).execute(&mut p);
+        Instr::Number(9).execute(&mut p);
+        Instr::Shl.execute(&mut p);
+        assert_eq!(p.data_pop(), 6);
+    }
+
+    #[test]
+    fn test_instr_shr() {
+        let mut p = Processor::new();
+        Instr::Number(12).execute(&mut p);
+        Instr::Number(2).execute(&mut p);
+        Instr::Shr.execute(&mut p);
+        assert_eq!(p.data_pop(), 3);
+    }
+
+    #[test]
+    fn test_instr_shr_wraps_amount() {
+        let mut p = Processor::new();
+        Instr::Number(12).execute(&mut p);
+        Instr::Number(10).execute(&mut p);
+        Instr::Shr.execute(&mut p);
+        assert_eq!(p.data_pop(), 3);
+    }
+
+    #[test]
+    fn test_instr_rot() {
+        let mut p = Processor::new();
+        Instr::Number(1).execute(&mut p);
+        Instr::Number(2).execute(&mut p);
+        Instr::Number(3).execute(&mut p);
+        Instr::Rot.execute(&mut p);
+        assert_eq!(p.data_pop(), 1);
+        assert_eq!(p.data_pop(), 3);
+        assert_eq!(p.data_pop(), 2);
+    }
+
+    #[test]
+    fn test_instr_rot_underflow() {
+        let mut p = Processor::new();
+        Instr::Number(5).execute(&mut p);
+        Instr::Rot.execute(&mut p);
+        assert_eq!(p.data_pop(), 0);
+        assert_eq!(p.data_pop(), 5);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_instr_zero() {
+        let mut p = Processor::new();
+        Instr::Zero.execute(&mut p);
+        assert_eq!(p.data_stack_index, 1);
+        assert_eq!(p.data_pop(), 0);
+    }
+
     #[test]
     fn test_instr_eq_true() {
         let mut p = Processor::new();
@@ -694,6 +1945,28 @@ mod tests {
         assert_eq!(p.gene_index, 2);
     }
 
+    #[test]
+    fn test_labels_across_calls() {
+        let mut p = Processor::with_config(VmConfig {
+            call_stack_size: 2,
+            ..VmConfig::default()
+        });
+        for gene_index in 1..=3 {
+            p.labels_mut()[0] = gene_index;
+            assert!(p.call_push(gene_index));
+            assert_eq!(p.labels(), &[0, 0, 0, 0]);
+        }
+        // the third call compressed the call stack, dropping gene 0
+        assert_eq!(p.call_stack()[0].gene_index(), 1);
+        assert_eq!(p.labels_at(0), &[2, 0, 0, 0]);
+        assert_eq!(p.labels_at(1), &[3, 0, 0, 0]);
+        p.call_pop();
+        assert_eq!(p.labels(), &[3, 0, 0, 0]);
+        p.call_pop();
+        assert_eq!(p.gene_index(), 1);
+        assert_eq!(p.labels(), &[2, 0, 0, 0]);
+    }
+
     #[test]
     fn test_instr_cond_true() {
         let mut p = Processor::new();
@@ -731,7 +2004,7 @@ mod tests {
             ],
         );
         let mut p = Processor::new();
-        p.execute(&c, 7);
+        p.execute(&mut c, 7);
         // the 7 should be there two times because of the jump
         assert_eq!(p.data_pop(), 7);
         assert_eq!(p.data_pop(), 7);
@@ -756,7 +2029,7 @@ mod tests {
             ],
         );
         let mut p = Processor::new();
-        p.execute(&c, 9);
+        p.execute(&mut c, 9);
         assert_eq!(p.data_pop(), 2);
         assert_eq!(p.data_pop(), 2);
         assert_eq!(p.data_pop(), 1);
@@ -764,98 +2037,1325 @@ mod tests {
     }
 
     #[test]
-    fn test_call_and_return_in_cell() {
+    fn test_jump_rel() {
         let mut c = Cell::new();
-        c.set_gene(1, vec![Instr::Number(3), Instr::Add, Instr::Return]);
         c.set_gene(
             0,
             vec![
                 Instr::Number(5),
-                Instr::Number(1),
-                Instr::Call,
-                Instr::Number(10),
-                Instr::Add,
+                Instr::Number(7),
+                Instr::Number(254), // -2
+                Instr::JumpRel,     // jump back to =7
             ],
         );
         let mut p = Processor::new();
-        p.execute(&c, 8);
-        assert_eq!(p.gene_index, 0);
-        // assert_eq!(p.data_stack, [0; DATA_STACK_SIZE]);
-        assert_eq!(p.data_pop(), 18);
+        p.execute(&mut c, 5);
+        assert_eq!(p.pc, 2);
+        assert_eq!(p.data_pop(), 7);
+        assert_eq!(p.data_pop(), 7);
+        assert_eq!(p.data_pop(), 5);
+        assert_eq!(p.data_pop(), 0);
     }
 
     #[test]
-    fn test_call_and_return_in_cell_implicit_return() {
+    fn test_jump_rel_forward() {
         let mut c = Cell::new();
-        c.set_gene(1, vec![Instr::Number(3), Instr::Add]);
         c.set_gene(
             0,
             vec![
-                Instr::Number(5),
+                Instr::Number(3),
+                Instr::JumpRel, // skip the =2
                 Instr::Number(1),
-                Instr::Call,
-                Instr::Number(10),
-                Instr::Add,
+                Instr::Number(2),
+                Instr::Number(3),
             ],
         );
         let mut p = Processor::new();
-        // should get us well into noop land but not beyond the end of gene 0
-        p.execute(&c, 50);
-        // assert_eq!(p.data_stack, [0; DATA_STACK_SIZE]);
-        assert_eq!(p.gene_index, 0);
-        assert_eq!(p.data_pop(), 18);
+        p.execute(&mut c, 3);
+        assert_eq!(p.data_pop(), 3);
+        assert_eq!(p.data_pop(), 0);
     }
 
     #[test]
-    fn test_jump_and_call_and_return_in_cell() {
+    fn test_jump_rel_wraps() {
         let mut c = Cell::new();
-        c.set_gene(1, vec![Instr::Number(3), Instr::Add, Instr::Return]);
         c.set_gene(
             0,
             vec![
-                Instr::Number(5),
-                Instr::Number(3),
-                Instr::Label,
                 Instr::Number(1),
-                Instr::Call,
-                Instr::Number(10),
-                Instr::Add,
-                Instr::Number(3),
-                Instr::Jump,
+                Instr::Number(127),
+                Instr::JumpRel, // 2 + 127 wraps to 1
             ],
         );
         let mut p = Processor::new();
-        p.execute(&c, 13);
-        assert_eq!(p.labels, [0, 0, 0, 3]);
-        assert_eq!(p.pc, 4);
-        // we should land just after the label
-        assert_eq!(p.data_pop(), 1);
-        assert_eq!(p.data_pop(), 18);
+        p.execute(&mut c, 3);
+        assert_eq!(p.pc, (2 + 127) % GENE_SIZE);
+        let mut p = Processor::new();
+        c.set_gene(0, vec![Instr::Number(128), Instr::JumpRel]);
+        p.execute(&mut c, 2);
+        // -128 goes around the gene four times
+        assert_eq!(p.pc, 1);
     }
 
     #[test]
-    fn test_return_from_gene0() {
+    fn test_jump_abs() {
         let mut c = Cell::new();
-        c.set_gene(0, vec![Instr::Number(1), Instr::Return]);
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(5),
+                Instr::Number(7),
+                Instr::Number(1),
+                Instr::JumpAbs, // jump to =7
+            ],
+        );
         let mut p = Processor::new();
-        p.execute(&c, 3);
-        // history is lost by returning from the main gene,
-        // so it should be just a single 1 on the stack
-        assert_eq!(p.data_pop(), 1);
+        p.execute(&mut c, 7);
+        assert_eq!(p.data_pop(), 7);
+        assert_eq!(p.data_pop(), 7);
+        assert_eq!(p.data_pop(), 5);
         assert_eq!(p.data_pop(), 0);
+        c.set_gene(
+            0,
+            vec![Instr::Number((GENE_SIZE + 1) as u8), Instr::JumpAbs],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 2);
+        assert_eq!(p.pc, 1);
     }
 
     #[test]
-    fn test_implicit_return_from_gene0() {
+    fn test_jump_to_end_of_gene_returns() {
         let mut c = Cell::new();
-        c.set_gene(0, vec![Instr::Number(1)]);
+        c.set_gene(0, vec![Instr::Number(1), Instr::Call, Instr::Number(9)]);
+        let mut gene = vec![Instr::Noop; GENE_SIZE];
+        gene[0] = Instr::Number((GENE_SIZE - 1) as u8);
+        gene[1] = Instr::JumpAbs;
+        gene[GENE_SIZE - 1] = Instr::Number(8);
+        c.set_gene(1, gene);
         let mut p = Processor::new();
-        p.execute(&c, 50);
-        // history is lost by returning from the main gene,
+        // =1 Call =31 JumpAbs =8, then the implicit return to =9
+        p.execute(&mut c, 6);
+        assert_eq!(p.gene_index, 0);
+        assert_eq!(p.data_pop(), 9);
+        assert_eq!(p.data_pop(), 8);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_set_loop_and_loop() {
+        let mut c = Cell::new();
+        // count down from 3
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(3),
+                Instr::SetLoop,
+                Instr::Dec,
+                Instr::Dup,
+                Instr::Loop,
+                Instr::Number(9),
+            ],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 8);
+        // back at the start of the loop for the third time
+        assert_eq!(p.loop_start, 2);
+        assert_eq!(p.pc, 2);
+        assert_eq!(p.data_stack(), &[1]);
+        p.execute(&mut c, 4);
+        assert_eq!(p.data_pop(), 9);
+        assert_eq!(p.data_pop(), 0);
+        assert_eq!(p.data_stack_index, 0);
+    }
+
+    #[test]
+    fn test_loop_without_set_loop() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Number(7), Instr::Number(1), Instr::Loop]);
+        let mut p = Processor::new();
+        p.execute(&mut c, 4);
+        // jumps back to the start of the gene
+        assert_eq!(p.pc, 1);
+        assert_eq!(p.data_pop(), 7);
+        assert_eq!(p.data_pop(), 7);
+    }
+
+    #[test]
+    fn test_loop_restored_after_call() {
+        let mut c = Cell::new();
+        // gene 1 has a loop of its own
+        c.set_gene(1, vec![Instr::SetLoop, Instr::Zero, Instr::Loop]);
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(2),
+                Instr::SetLoop,
+                Instr::Number(1),
+                Instr::Call,
+                Instr::Dec,
+                Instr::Dup,
+                Instr::Loop,
+            ],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 5);
+        assert_eq!(p.gene_index, 1);
+        assert_eq!(p.loop_start, 1);
+        assert_eq!(p.call_stack[0].loop_start, 2);
+        // finish gene 1, return, and loop in gene 0
+        p.execute(&mut c, GENE_SIZE - 1 + 3);
+        assert_eq!(p.gene_index, 0);
+        assert_eq!(p.loop_start, 2);
+        assert_eq!(p.pc, 2);
+        assert_eq!(p.data_pop(), 1);
+    }
+
+    fn templates() -> VmConfig {
+        VmConfig {
+            addressing: Addressing::Templates,
+            ..VmConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_template_jump_forward() {
+        let mut c = Cell::with_config(templates());
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(1),
+                Instr::Jump,
+                Instr::Nop0,
+                Instr::Nop1,
+                Instr::Number(2),
+                Instr::Nop1,
+                Instr::Nop0,
+                Instr::Number(3),
+            ],
+        );
+        let mut p = Processor::with_config(templates());
+        p.execute(&mut c, 3);
+        assert_eq!(p.data_pop(), 3);
+        assert_eq!(p.data_pop(), 1);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_template_jump_backward() {
+        let mut c = Cell::with_config(templates());
+        c.set_gene(
+            0,
+            vec![
+                Instr::Nop1,
+                Instr::Nop0,
+                Instr::Number(5),
+                Instr::Jump,
+                Instr::Nop0,
+                Instr::Nop1,
+                Instr::Number(6),
+            ],
+        );
+        let mut p = Processor::with_config(templates());
+        p.execute(&mut c, 5);
+        assert_eq!(p.pc, 3);
+        assert_eq!(p.data_pop(), 5);
+        assert_eq!(p.data_pop(), 5);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_template_jump_nearest() {
+        let mut c = Cell::with_config(templates());
+        c.set_gene(
+            0,
+            vec![
+                Instr::Nop1,
+                Instr::Number(1),
+                Instr::Number(2),
+                Instr::Jump,
+                Instr::Nop0,
+                Instr::Number(3),
+                Instr::Number(4),
+                Instr::Number(5),
+                Instr::Nop1,
+            ],
+        );
+        let mut p = Processor::with_config(templates());
+        // skip the first instructions
+        p.pc = 3;
+        p.step(&mut c);
+        // the backward Nop1 is closer than the forward one
+        assert_eq!(p.pc, 1);
+    }
+
+    #[test]
+    fn test_template_jump_not_found() {
+        let mut c = Cell::with_config(templates());
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(1),
+                Instr::Jump,
+                Instr::Nop0,
+                Instr::Nop0,
+                Instr::Number(2),
+            ],
+        );
+        let mut p = Processor::with_config(templates());
+        p.execute(&mut c, 2);
+        // we continue after the template; the stack isn't used
+        assert_eq!(p.pc, 4);
+        assert_eq!(p.data_stack(), &[1]);
+    }
+
+    #[test]
+    fn test_template_jump_without_template() {
+        let mut c = Cell::with_config(templates());
+        c.set_gene(0, vec![Instr::Jump, Instr::Number(2)]);
+        let mut p = Processor::with_config(templates());
+        p.execute(&mut c, 2);
+        assert_eq!(p.data_pop(), 2);
+    }
+
+    #[test]
+    fn test_template_call() {
+        let mut c = Cell::with_config(templates());
+        c.set_gene(
+            0,
+            vec![Instr::Number(1), Instr::Call, Instr::Nop0, Instr::Number(9)],
+        );
+        c.set_gene(2, vec![Instr::Nop0, Instr::Number(8)]);
+        c.set_gene(3, vec![Instr::Nop1, Instr::Number(7), Instr::Return]);
+        let mut p = Processor::with_config(templates());
+        p.execute(&mut c, 2);
+        assert_eq!(p.gene_index, 3);
+        assert_eq!(p.pc, 1);
+        p.execute(&mut c, 3);
+        assert_eq!(p.data_pop(), 9);
+        assert_eq!(p.data_pop(), 7);
+        assert_eq!(p.data_pop(), 1);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_template_call_not_found() {
+        let mut c = Cell::with_config(templates());
+        c.set_gene(
+            0,
+            vec![Instr::Call, Instr::Nop0, Instr::Nop0, Instr::Number(9)],
+        );
+        let mut p = Processor::with_config(templates());
+        p.execute(&mut c, 2);
+        assert_eq!(p.gene_index, 0);
+        assert_eq!(p.call_stack_index, 0);
+        assert_eq!(p.data_pop(), 9);
+    }
+
+    #[test]
+    fn test_templates_are_noops_with_labels() {
+        let mut c = Cell::new();
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(0),
+                Instr::Jump,
+                Instr::Nop0,
+                Instr::Nop1,
+                Instr::Number(3),
+            ],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 2);
+        // jump to label 0, the start of the gene
+        assert_eq!(p.pc, 0);
+    }
+
+    fn interrupt_cell() -> Cell {
+        let mut c = Cell::new();
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(2),
+                Instr::OnContact,
+                Instr::Number(3),
+                Instr::OnInput,
+                Instr::Number(7),
+            ],
+        );
+        c.set_gene(2, vec![Instr::Number(10), Instr::Add, Instr::Return]);
+        c.set_gene(3, vec![Instr::Drop, Instr::Return]);
+        c
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut c = interrupt_cell();
+        let mut p = Processor::new();
+        p.execute(&mut c, 4);
+        assert!(p.interrupt(Event::Contact(5)));
+        assert_eq!(p.gene_index, 2);
+        assert_eq!(p.data_stack(), &[5]);
+        p.execute(&mut c, 3);
+        // back where we were interrupted
+        assert_eq!(p.gene_index, 0);
+        assert_eq!(p.pc, 4);
+        assert_eq!(p.data_stack(), &[15]);
+        assert!(p.interrupt(Event::Input(1)));
+        assert_eq!(p.gene_index, 3);
+        p.execute(&mut c, 3);
+        assert_eq!(p.data_stack(), &[15, 7]);
+    }
+
+    #[test]
+    fn test_interrupt_not_registered() {
+        let mut c = interrupt_cell();
+        let mut p = Processor::new();
+        p.execute(&mut c, 2);
+        assert!(!p.interrupt(Event::Input(1)));
+        assert!(!p.interrupt(Event::Timer));
+        assert_eq!(p.gene_index, 0);
+        assert_eq!(p.pc, 2);
+        p.stop();
+        assert!(!p.interrupt(Event::Contact(0)));
+    }
+
+    #[test]
+    fn test_interrupt_drops_false_cond() {
+        let mut c = interrupt_cell();
+        let mut p = Processor::new();
+        p.execute(&mut c, 2);
+        p.cond = false;
+        p.interrupt(Event::Contact(5));
+        p.execute(&mut c, 2);
+        assert_eq!(p.data_stack(), &[15]);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Number(1), Instr::Number(3), Instr::SetTimer]);
+        c.set_gene(1, vec![Instr::Number(9)]);
+        let mut p = Processor::new();
+        // the timer is off
+        p.tick_timer();
+        p.execute(&mut c, 3);
+        assert_eq!(p.timer(), 3);
+        p.tick_timer();
+        p.tick_timer();
+        assert_eq!(p.gene_index, 0);
+        p.tick_timer();
+        assert_eq!(p.timer(), 0);
+        assert_eq!(p.gene_index, 1);
+        // the timer doesn't put anything on the stack
+        p.execute(&mut c, 1);
+        assert_eq!(p.data_stack(), &[9]);
+        // it only runs out once
+        p.tick_timer();
+        assert_eq!(p.call_stack().len(), 1);
+    }
+
+    #[test]
+    fn test_start_forgets_interrupts() {
+        let mut c = interrupt_cell();
+        let mut p = Processor::new();
+        p.execute(&mut c, 2);
+        // returning from the main gene keeps them
+        p.pc = GENE_SIZE;
+        p.step(&mut c);
+        assert!(p.interrupt(Event::Contact(0)));
+        p.start(0);
+        assert!(!p.interrupt(Event::Contact(0)));
+    }
+
+    #[test]
+    fn test_call_and_return_in_cell() {
+        let mut c = Cell::new();
+        c.set_gene(1, vec![Instr::Number(3), Instr::Add, Instr::Return]);
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(5),
+                Instr::Number(1),
+                Instr::Call,
+                Instr::Number(10),
+                Instr::Add,
+            ],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 8);
+        assert_eq!(p.gene_index, 0);
+        // assert_eq!(p.data_stack, [0; DATA_STACK_SIZE]);
+        assert_eq!(p.data_pop(), 18);
+    }
+
+    #[test]
+    fn test_call_and_return_in_cell_implicit_return() {
+        let mut c = Cell::new();
+        c.set_gene(1, vec![Instr::Number(3), Instr::Add]);
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(5),
+                Instr::Number(1),
+                Instr::Call,
+                Instr::Number(10),
+                Instr::Add,
+            ],
+        );
+        let mut p = Processor::new();
+        // should get us well into noop land but not beyond the end of gene 0
+        p.execute(&mut c, 50);
+        // assert_eq!(p.data_stack, [0; DATA_STACK_SIZE]);
+        assert_eq!(p.gene_index, 0);
+        assert_eq!(p.data_pop(), 18);
+    }
+
+    #[test]
+    fn test_jump_and_call_and_return_in_cell() {
+        let mut c = Cell::new();
+        c.set_gene(1, vec![Instr::Number(3), Instr::Add, Instr::Return]);
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(5),
+                Instr::Number(3),
+                Instr::Label,
+                Instr::Number(1),
+                Instr::Call,
+                Instr::Number(10),
+                Instr::Add,
+                Instr::Number(3),
+                Instr::Jump,
+            ],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 13);
+        assert_eq!(p.labels(), &[0, 0, 0, 3]);
+        assert_eq!(p.pc, 4);
+        // we should land just after the label
+        assert_eq!(p.data_pop(), 1);
+        assert_eq!(p.data_pop(), 18);
+    }
+
+    #[test]
+    fn test_return_from_gene0() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Number(1), Instr::Return]);
+        let mut p = Processor::new();
+        p.execute(&mut c, 3);
+        // history is lost by returning from the main gene,
+        // so it should be just a single 1 on the stack
+        assert_eq!(p.data_pop(), 1);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_implicit_return_from_gene0() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Number(1)]);
+        let mut p = Processor::new();
+        p.execute(&mut c, 50);
+        // history is lost by returning from the main gene,
         // so it should be just a single 1 on the stack
         assert_eq!(p.data_pop(), 1);
         assert_eq!(p.data_pop(), 0);
     }
 
+    #[test]
+    fn test_implicit_return_from_start_gene() {
+        let mut c = Cell::new();
+        c.set_gene(2, vec![Instr::Number(2), Instr::Number(1), Instr::Call]);
+        c.set_gene(1, vec![Instr::Add]);
+        let mut p = Processor::new();
+        p.start(2);
+        p.execute(&mut c, GENE_SIZE * 2);
+        // the processor restarts on gene 2, not on gene 0
+        assert_eq!(p.next_location(), (2, 0));
+        assert_eq!(p.step(&mut c), Instr::Number(2));
+        assert_eq!(p.gene_index(), 2);
+        assert_eq!(p.data_stack(), &[2]);
+    }
+
+    #[test]
+    fn test_next_instruction() {
+        let mut c = Cell::new();
+        c.set_gene(1, vec![Instr::Number(3), Instr::Add]);
+        c.set_gene(0, vec![Instr::Number(1), Instr::Call, Instr::Dup]);
+        let mut p = Processor::new();
+        assert_eq!(p.next_instruction(&c), Instr::Number(1));
+        p.execute(&mut c, 2);
+        assert_eq!(p.next_instruction(&c), Instr::Number(3));
+        p.execute(&mut c, GENE_SIZE);
+        // implicit return from gene 1
+        assert_eq!(p.next_instruction(&c), Instr::Dup);
+        assert_eq!(p.step(&mut c), Instr::Dup);
+        p.execute(&mut c, GENE_SIZE - 3);
+        // implicit return from gene 0 starts it again
+        assert_eq!(p.next_instruction(&c), Instr::Number(1));
+    }
+
+    #[test]
+    fn test_instr_read() {
+        let mut c = Cell::new();
+        c.set_gene(2, vec![Instr::Noop, Instr::Number(7), Instr::Add]);
+        let mut p = Processor::new();
+        Instr::Number(2).execute_in(&mut p, &mut c);
+        Instr::Number(1).execute_in(&mut p, &mut c);
+        Instr::Read.execute_in(&mut p, &mut c);
+        assert_eq!(p.data_pop(), Instr::Number(7).to_value());
+        Instr::Number(2).execute_in(&mut p, &mut c);
+        Instr::Number(2).execute_in(&mut p, &mut c);
+        Instr::Read.execute_in(&mut p, &mut c);
+        assert_eq!(p.data_pop(), Instr::Add.to_value());
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_instr_read_out_of_range() {
+        let mut c = Cell::new();
+        c.set_gene(1, vec![Instr::Noop, Instr::Dup]);
+        let mut p = Processor::new();
+        Instr::Number(GENE_AMOUNT as u8 + 1).execute_in(&mut p, &mut c);
+        Instr::Number(GENE_SIZE as u8 + 1).execute_in(&mut p, &mut c);
+        Instr::Read.execute_in(&mut p, &mut c);
+        assert_eq!(p.data_pop(), Instr::Dup.to_value());
+    }
+
+    #[test]
+    fn test_instr_write() {
+        let mut c = Cell::new();
+        c.add_materials(1);
+        let mut p = Processor::new();
+        Instr::Number(3).execute_in(&mut p, &mut c);
+        Instr::Number(4).execute_in(&mut p, &mut c);
+        Instr::Number(Instr::Add.to_value()).execute_in(&mut p, &mut c);
+        Instr::Write.execute_in(&mut p, &mut c);
+        assert_eq!(c.gene(3)[4], Instr::Add);
+        assert_eq!(c.materials(), 0);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_instr_write_without_materials() {
+        let mut c = Cell::new();
+        let mut p = Processor::new();
+        Instr::Number(3).execute_in(&mut p, &mut c);
+        Instr::Number(4).execute_in(&mut p, &mut c);
+        Instr::Number(5).execute_in(&mut p, &mut c);
+        Instr::Write.execute_in(&mut p, &mut c);
+        assert_eq!(c, Cell::new());
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_instr_write_noop_is_free() {
+        let mut c = Cell::new();
+        c.set_gene(3, vec![Instr::Add; 5]);
+        let mut p = Processor::new();
+        Instr::Number(3).execute_in(&mut p, &mut c);
+        Instr::Number(4).execute_in(&mut p, &mut c);
+        Instr::Number(Instr::Noop.to_value()).execute_in(&mut p, &mut c);
+        Instr::Write.execute_in(&mut p, &mut c);
+        assert_eq!(c.gene(3)[4], Instr::Noop);
+        assert_eq!(c.materials(), 0);
+    }
+
+    #[test]
+    fn test_load_and_store() {
+        let mut c = Cell::new();
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(42),
+                Instr::Number(3),
+                Instr::Store,
+                Instr::Number(11), // wraps to register 3
+                Instr::Load,
+            ],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 5);
+        assert_eq!(c.register(3), 42);
+        assert_eq!(p.data_pop(), 42);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_test_and_set() {
+        let mut c = Cell::new();
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(2),
+                Instr::TestAndSet,
+                Instr::Number(2),
+                Instr::TestAndSet,
+            ],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 4);
+        // only the first one got the lock
+        assert_eq!(p.data_pop(), 1);
+        assert_eq!(p.data_pop(), 0);
+        assert_eq!(c.register(2), 1);
+    }
+
+    #[test]
+    fn test_store_cond_false() {
+        let mut c = Cell::new();
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(1),
+                Instr::Number(0),
+                Instr::Number(0),
+                Instr::Cond,
+                Instr::Store,
+            ],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 5);
+        assert_eq!(c.register(0), 0);
+    }
+
+    #[test]
+    fn test_instr_write_cond_false() {
+        let mut c = Cell::new();
+        c.add_materials(1);
+        let mut p = Processor::new();
+        Instr::Number(3).execute_in(&mut p, &mut c);
+        Instr::Number(4).execute_in(&mut p, &mut c);
+        Instr::Number(5).execute_in(&mut p, &mut c);
+        Instr::Number(0).execute_in(&mut p, &mut c);
+        Instr::Cond.execute_in(&mut p, &mut c);
+        Instr::Write.execute_in(&mut p, &mut c);
+        assert_eq!(c.gene(3)[4], Instr::Noop);
+        assert_eq!(c.materials(), 1);
+        assert_eq!(p.data_pop(), 5);
+    }
+
+    #[test]
+    fn test_replicator() {
+        let mut c = load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/genomes/replicator.txt"
+        ))
+        .unwrap();
+        // exactly enough materials for a single copy
+        let used = (0..8)
+            .flat_map(|gene_index| c.gene(gene_index).iter())
+            .filter(|instr| **instr != Instr::Noop)
+            .count();
+        c.add_materials(used as u32);
+        let mut p = Processor::new();
+        p.execute(&mut c, 5000);
+        for gene_index in 0..8 {
+            assert_eq!(c.gene(gene_index), c.gene(gene_index + 8));
+        }
+        assert_eq!(c.materials(), 0);
+    }
+
+    #[test]
+    fn test_copy_gene() {
+        // copy gene 1 into gene 2, value by value
+        let mut c = Cell::new();
+        c.add_materials(100);
+        c.set_gene(1, vec![Instr::Number(3), Instr::Add, Instr::Return]);
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(0),
+                Instr::Number(0),
+                Instr::Label, // index is on the stack
+                Instr::Dup,
+                Instr::Number(2),
+                Instr::Swap,
+                Instr::Number(1),
+                Instr::Over, // index 2 index 1 index
+                Instr::Read,
+                Instr::Write,
+                Instr::Number(1),
+                Instr::Add,
+                Instr::Number(0),
+                Instr::Jump,
+            ],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 3 + 3 * 11);
+        assert_eq!(c.gene(2), c.gene(1));
+        assert_eq!(c.materials(), 97);
+    }
+
+    #[test]
+    fn test_instr_push_instr() {
+        let mut p = Processor::new();
+        Instr::Number(Instr::Dup.to_value()).execute(&mut p);
+        Instr::PushInstr.execute(&mut p);
+        assert_eq!(p.data_pop(), 0);
+        assert_eq!(p.instruction_pop(), Instr::Dup);
+    }
+
+    #[test]
+    fn test_instr_pop_instr() {
+        let mut p = Processor::new();
+        p.instruction_push(Instr::Number(7));
+        Instr::PopInstr.execute(&mut p);
+        assert_eq!(p.data_pop(), 7);
+        assert_eq!(p.instruction_stack_index, 0);
+        // underflow gives a Noop
+        Instr::PopInstr.execute(&mut p);
+        assert_eq!(p.data_pop(), Instr::Noop.to_value());
+    }
+
+    #[test]
+    fn test_instr_write_gene() {
+        let mut c = Cell::new();
+        c.add_materials(10);
+        c.set_gene(4, vec![Instr::Add; GENE_SIZE]);
+        let mut p = Processor::new();
+        p.instruction_push(Instr::Number(3));
+        p.instruction_push(Instr::Noop);
+        p.instruction_push(Instr::Sub);
+        Instr::Number(4).execute_in(&mut p, &mut c);
+        Instr::WriteGene.execute_in(&mut p, &mut c);
+        assert_eq!(
+            &c.gene(4)[..4],
+            &[Instr::Number(3), Instr::Noop, Instr::Sub, Instr::Noop]
+        );
+        assert_eq!(c.gene(4)[GENE_SIZE - 1], Instr::Noop);
+        assert_eq!(c.materials(), 8);
+        assert_eq!(p.instruction_stack_index, 0);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_instr_write_gene_without_materials() {
+        let mut c = Cell::new();
+        c.add_materials(1);
+        c.set_gene(4, vec![Instr::Add; 4]);
+        let mut p = Processor::new();
+        p.instruction_push(Instr::Dup);
+        p.instruction_push(Instr::Swap);
+        p.instruction_push(Instr::Noop);
+        Instr::Number(4).execute_in(&mut p, &mut c);
+        Instr::WriteGene.execute_in(&mut p, &mut c);
+        // Swap couldn't be written, but the Noop could
+        assert_eq!(
+            &c.gene(4)[..4],
+            &[Instr::Dup, Instr::Add, Instr::Noop, Instr::Noop]
+        );
+        assert_eq!(c.materials(), 0);
+    }
+
+    #[test]
+    fn test_copy_gene_with_instruction_stack() {
+        // copy gene 1 into gene 2 through the instruction stack
+        let mut c = Cell::new();
+        c.add_materials(100);
+        c.set_gene(1, vec![Instr::Number(3), Instr::Add, Instr::Return]);
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(0),
+                Instr::Number(0),
+                Instr::Label, // index is on the stack
+                Instr::Number(1),
+                Instr::Over, // index 1 index
+                Instr::Read,
+                Instr::PushInstr,
+                Instr::Number(1),
+                Instr::Add,
+                Instr::Dup,
+                Instr::Number(3),
+                Instr::Ne,
+                Instr::Number(0),
+                Instr::Swap,
+                Instr::Cond,
+                Instr::Jump, // jumps to label 0 as long as index isn't 3
+                Instr::Number(2),
+                Instr::WriteGene,
+            ],
+        );
+        let mut p = Processor::new();
+        p.execute(&mut c, 3 + 3 * 13 + 2);
+        assert_eq!(c.gene(2), c.gene(1));
+        assert_eq!(c.materials(), 97);
+    }
+
+    #[test]
+    fn test_instr_out() {
+        let mut p = Processor::new();
+        Instr::Number(2).execute(&mut p);
+        Instr::SelectOut.execute(&mut p);
+        Instr::Number(5).execute(&mut p);
+        Instr::Out.execute(&mut p);
+        assert_eq!(p.data_pop(), 0);
+        assert_eq!(p.output_mut(2).pop(), Some(5));
+        assert_eq!(p.output_mut(2).pop(), None);
+        assert_eq!(p.output_mut(0).pop(), None);
+    }
+
+    #[test]
+    fn test_instr_select_out_of_range() {
+        let mut p = Processor::new();
+        Instr::Number(PORT_AMOUNT as u8 + 1).execute(&mut p);
+        Instr::SelectOut.execute(&mut p);
+        Instr::Number(5).execute(&mut p);
+        Instr::Out.execute(&mut p);
+        assert_eq!(p.output_mut(1).pop(), Some(5));
+    }
+
+    #[test]
+    fn test_instr_out_full() {
+        let mut p = Processor::new();
+        Instr::OutFull.execute(&mut p);
+        assert_eq!(p.data_pop(), 0);
+        for i in 0..PORT_QUEUE_SIZE {
+            Instr::Number(i as u8).execute(&mut p);
+            Instr::Out.execute(&mut p);
+        }
+        Instr::OutFull.execute(&mut p);
+        assert_eq!(p.data_pop(), 1);
+        // this one is lost
+        Instr::Number(100).execute(&mut p);
+        Instr::Out.execute(&mut p);
+        assert_eq!(p.output_mut(0).len(), PORT_QUEUE_SIZE);
+        assert_eq!(p.output_mut(0).pop(), Some(0));
+    }
+
+    #[test]
+    fn test_instr_in() {
+        let mut p = Processor::new();
+        p.input_mut(3).push(7);
+        p.input_mut(3).push(8);
+        Instr::Number(3).execute(&mut p);
+        Instr::SelectIn.execute(&mut p);
+        Instr::In.execute(&mut p);
+        Instr::In.execute(&mut p);
+        assert_eq!(p.data_pop(), 8);
+        assert_eq!(p.data_pop(), 7);
+        // empty input gives 0
+        Instr::In.execute(&mut p);
+        assert_eq!(p.data_pop(), 0);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_instr_in_empty() {
+        let mut p = Processor::new();
+        Instr::InEmpty.execute(&mut p);
+        assert_eq!(p.data_pop(), 1);
+        p.input_mut(0).push(7);
+        Instr::InEmpty.execute(&mut p);
+        assert_eq!(p.data_pop(), 0);
+    }
+
+    #[test]
+    fn test_instr_rand() {
+        let mut p = Processor::new();
+        p.set_seed(42);
+        let mut other = Processor::new();
+        other.set_seed(42);
+        for _i in 0..10 {
+            Instr::Rand.execute(&mut p);
+            Instr::Rand.execute(&mut other);
+        }
+        // the same seed gives the same numbers
+        assert_eq!(p, other);
+        let values = p.data_stack();
+        assert!(values.iter().any(|value| *value != values[0]));
+    }
+
+    #[test]
+    fn test_instr_rand_seed() {
+        let mut p = Processor::new();
+        p.set_seed(1);
+        let mut other = Processor::new();
+        other.set_seed(2);
+        // the generator state is part of equality
+        assert_ne!(p, other);
+        for _i in 0..4 {
+            Instr::Rand.execute(&mut p);
+            Instr::Rand.execute(&mut other);
+        }
+        assert_ne!(p.data_stack(), other.data_stack());
+    }
+
+    #[test]
+    fn test_instr_rand_range() {
+        let mut p = Processor::new();
+        for _i in 0..100 {
+            p.data_push(3);
+            Instr::RandRange.execute(&mut p);
+            assert!(p.data_pop() < 3);
+        }
+    }
+
+    #[test]
+    fn test_instr_rand_range_empty() {
+        let mut p = Processor::new();
+        p.data_push(0);
+        Instr::RandRange.execute(&mut p);
+        assert_eq!(p.data_pop(), 0);
+        assert_eq!(p.data_stack_index, 0);
+    }
+
+    #[test]
+    fn test_rand_survives_restart() {
+        let mut p = Processor::new();
+        Instr::Rand.execute(&mut p);
+        let first = p.data_pop();
+        p.start(0);
+        Instr::Rand.execute(&mut p);
+        assert_ne!(p.data_pop(), first);
+    }
+
+    #[test]
+    fn test_call_from_last_slot() {
+        let mut c = Cell::new();
+        c.set_gene(1, vec![Instr::Number(3)]);
+        let mut gene0 = vec![Instr::Noop; GENE_SIZE - 2];
+        gene0.push(Instr::Number(1));
+        gene0.push(Instr::Call);
+        c.set_gene(0, gene0);
+        let mut p = Processor::new();
+        p.execute(&mut c, 2 * GENE_SIZE);
+        // the implicit return from gene 1 lands at the end of gene 0, so
+        // we return from gene 0 too, which starts it again
+        assert_eq!(p.next_instruction(&c), Instr::Noop);
+        p.step(&mut c);
+        assert_eq!(p.gene_index, 0);
+        assert_eq!(p.pc, 1);
+        assert_eq!(p.call_stack_index, 0);
+    }
+
+    #[test]
+    fn test_set_gene_too_long() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Add; GENE_SIZE + 3]);
+        assert_eq!(c.gene(0), &[Instr::Add; GENE_SIZE]);
+    }
+
+    #[test]
+    fn test_gene_wraps() {
+        let mut c = Cell::new();
+        c.set_gene(GENE_AMOUNT as u8 + 2, vec![Instr::Dup]);
+        assert_eq!(c.gene(2)[0], Instr::Dup);
+        c.gene_mut(255)[1] = Instr::Add;
+        assert_eq!(c.gene(255 % GENE_AMOUNT as u8)[1], Instr::Add);
+    }
+
+    #[test]
+    fn test_step_other_config() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Number(1)]);
+        let mut p = Processor::with_config(VmConfig {
+            data_stack_size: 4,
+            ..VmConfig::default()
+        });
+        assert_eq!(p.next_instruction(&c), Instr::Noop);
+        assert_eq!(p.step(&mut c), Instr::Noop);
+        assert!(!p.is_active());
+        assert_eq!(p.faults(), 1);
+        assert_eq!(p.pc(), 0);
+        assert!(p.data_stack().is_empty());
+    }
+
+    #[test]
+    fn test_stopped() {
+        let mut c = Cell::new();
+        c.add_materials(10);
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(0),
+                Instr::Number(0),
+                Instr::Number(9),
+                Instr::Write,
+            ],
+        );
+        let original = c.clone();
+        let mut p = Processor::new();
+        p.stop();
+        let stopped = p.clone();
+        p.execute(&mut c, 100);
+        assert_eq!(p.step(&mut c), Instr::Noop);
+        assert_eq!(p, stopped);
+        assert_eq!(c, original);
+    }
+
+    fn policy(stack_policy: StackPolicy) -> VmConfig {
+        VmConfig {
+            data_stack_size: 4,
+            call_stack_size: 2,
+            stack_policy,
+            ..VmConfig::default()
+        }
+    }
+
+    fn overflow(stack_policy: StackPolicy) -> Processor {
+        let mut c = Cell::with_config(policy(stack_policy));
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(1),
+                Instr::Number(2),
+                Instr::Number(3),
+                Instr::Number(4),
+                Instr::Number(5),
+            ],
+        );
+        let mut p = Processor::with_config(policy(stack_policy));
+        p.execute(&mut c, 5);
+        p
+    }
+
+    #[test]
+    fn test_overflow_compress() {
+        let p = overflow(StackPolicy::Compress);
+        assert_eq!(p.data_stack(), &[3, 4, 5]);
+        assert_eq!(p.faults(), 0);
+    }
+
+    #[test]
+    fn test_overflow_wrap() {
+        let p = overflow(StackPolicy::Wrap);
+        // only the oldest value is gone
+        assert_eq!(p.data_stack(), &[2, 3, 4, 5]);
+        assert_eq!(p.faults(), 0);
+    }
+
+    #[test]
+    fn test_overflow_drop_newest() {
+        let p = overflow(StackPolicy::DropNewest);
+        assert_eq!(p.data_stack(), &[1, 2, 3, 4]);
+        assert!(p.is_active());
+        assert_eq!(p.faults(), 0);
+    }
+
+    #[test]
+    fn test_overflow_fault() {
+        let p = overflow(StackPolicy::Fault);
+        assert_eq!(p.data_stack(), &[1, 2, 3, 4]);
+        assert!(!p.is_active());
+        assert_eq!(p.faults(), 1);
+        // execution stopped at the fault
+        assert_eq!(p.pc, 5);
+    }
+
+    #[test]
+    fn test_underflow() {
+        let mut c = Cell::with_config(policy(StackPolicy::DropNewest));
+        c.set_gene(0, vec![Instr::Add, Instr::Number(1)]);
+        let mut p = Processor::with_config(policy(StackPolicy::DropNewest));
+        p.execute(&mut c, 2);
+        assert_eq!(p.data_stack(), &[0, 1]);
+        assert_eq!(p.faults(), 0);
+    }
+
+    #[test]
+    fn test_underflow_fault() {
+        let mut c = Cell::with_config(policy(StackPolicy::Fault));
+        c.set_gene(0, vec![Instr::Add, Instr::Number(1)]);
+        let mut p = Processor::with_config(policy(StackPolicy::Fault));
+        p.execute(&mut c, 2);
+        // both pops underflow, but a stopped processor doesn't fault again
+        assert_eq!(p.faults(), 1);
+        assert!(!p.is_active());
+        // restarting doesn't forget the fault
+        p.start(0);
+        p.execute(&mut c, 1);
+        assert_eq!(p.faults(), 2);
+        p.start(0);
+        assert_eq!(p.faults(), 2);
+    }
+
+    fn call_overflow(stack_policy: StackPolicy) -> Processor {
+        let mut c = Cell::with_config(policy(stack_policy));
+        // every gene calls the next
+        for gene_index in 0..4 {
+            c.set_gene(gene_index, vec![Instr::Number(gene_index + 1), Instr::Call]);
+        }
+        let mut p = Processor::with_config(policy(stack_policy));
+        p.execute(&mut c, 6);
+        p
+    }
+
+    #[test]
+    fn test_call_overflow_wrap() {
+        let p = call_overflow(StackPolicy::Wrap);
+        assert_eq!(p.gene_index, 3);
+        let callers: Vec<u8> = p.call_stack().iter().map(|e| e.gene_index()).collect();
+        assert_eq!(callers, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_call_overflow_drop_newest() {
+        let p = call_overflow(StackPolicy::DropNewest);
+        // the third call didn't happen
+        assert_eq!(p.gene_index, 2);
+        assert_eq!(p.pc, 2);
+        let callers: Vec<u8> = p.call_stack().iter().map(|e| e.gene_index()).collect();
+        assert_eq!(callers, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_call_overflow_fault() {
+        let p = call_overflow(StackPolicy::Fault);
+        assert_eq!(p.gene_index, 2);
+        assert_eq!(p.faults(), 1);
+        assert!(!p.is_active());
+    }
+
+    #[test]
+    fn test_small_config() {
+        let config = VmConfig {
+            gene_size: 4,
+            gene_amount: 2,
+            data_stack_size: 3,
+            ..VmConfig::default()
+        };
+        let mut c = Cell::with_config(config);
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(1),
+                Instr::Number(2),
+                Instr::Number(3),
+                Instr::Number(4),
+            ],
+        );
+        c.set_gene(1, vec![Instr::Number(5); 6]);
+        assert_eq!(c.gene(1), &[Instr::Number(5); 4]);
+        let mut p = Processor::with_config(config);
+        p.execute(&mut c, 4);
+        // the stack overflowed; the top value was kept
+        assert_eq!(p.data_stack_index, 2);
+        assert_eq!(p.data_pop(), 4);
+        assert_eq!(p.data_pop(), 3);
+        assert_eq!(p.data_pop(), 0);
+        // calling gene 2 wraps around to gene 0
+        p.start(2);
+        assert_eq!(p.gene_index, 0);
+    }
+
+    #[test]
+    fn test_small_config_copy_gene() {
+        let config = VmConfig {
+            gene_size: 8,
+            gene_amount: 3,
+            ..VmConfig::default()
+        };
+        let mut c = Cell::with_config(config);
+        c.add_materials(10);
+        // read gene 1 slot 9, which wraps to slot 1, and write it to gene 2
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(2),
+                Instr::Number(0),
+                Instr::Number(1),
+                Instr::Number(9),
+                Instr::Read,
+                Instr::Write,
+            ],
+        );
+        c.set_gene(1, vec![Instr::Noop, Instr::Mul]);
+        let mut p = Processor::with_config(config);
+        p.execute(&mut c, 6);
+        assert_eq!(c.gene(2)[0], Instr::Mul);
+    }
+
     // q: should a return from gene 0 reset all the stacks?
+
+    fn assert_in_bounds(p: &Processor) {
+        assert!((p.gene_index as usize) < p.config.gene_amount);
+        assert!(p.pc <= p.config.gene_size);
+        assert!(p.data_stack_index <= p.config.data_stack_size);
+        assert!(p.call_stack_index <= p.config.call_stack_size);
+        assert!(p.instruction_stack_index <= p.config.instruction_stack_size);
+        assert!((p.output_port as usize) < PORT_AMOUNT);
+        assert!((p.input_port as usize) < PORT_AMOUNT);
+    }
+
+    proptest! {
+        // any byte string is a genome, so this runs random genomes
+        #[test]
+        fn test_random_genome_never_panics(
+            bytes in proptest::collection::vec(any::<u8>(), 0..1200),
+            gene_index in any::<u8>(),
+            materials in 0..100u32,
+            steps in 0..2000usize,
+        ) {
+            let mut c = decode_genes(&bytes, OPCODES.len() as u8, VmConfig::default());
+            c.add_materials(materials);
+            let mut p = Processor::new();
+            p.start(gene_index);
+            for _i in 0..steps {
+                let next = p.next_instruction(&c);
+                assert_eq!(p.step(&mut c), next);
+                assert_in_bounds(&p);
+            }
+        }
+
+        #[test]
+        fn test_random_genome_with_input_never_panics(
+            bytes in proptest::collection::vec(any::<u8>(), 0..1200),
+            inputs in proptest::collection::vec(any::<u8>(), 0..PORT_AMOUNT * PORT_QUEUE_SIZE),
+        ) {
+            let mut c = decode_genes(&bytes, OPCODES.len() as u8, VmConfig::default());
+            let mut p = Processor::new();
+            for (i, value) in inputs.iter().enumerate() {
+                p.input_mut((i % PORT_AMOUNT) as u8).push(*value);
+            }
+            for _i in 0..1000 {
+                p.step(&mut c);
+                assert_in_bounds(&p);
+            }
+        }
+
+        #[test]
+        fn test_random_config_never_panics(
+            bytes in proptest::collection::vec(any::<u8>(), 0..600),
+            gene_size in 1..=255usize,
+            gene_amount in 1..=255usize,
+            label_amount in 1..8usize,
+            data_stack_size in 1..40usize,
+            instruction_stack_size in 1..40usize,
+            call_stack_size in 1..40u8,
+            templates in any::<bool>(),
+            policy in 0..4usize,
+            gene_index in any::<u8>(),
+        ) {
+            let config = VmConfig {
+                gene_size,
+                gene_amount,
+                label_amount,
+                data_stack_size,
+                instruction_stack_size,
+                call_stack_size,
+                addressing: if templates {
+                    Addressing::Templates
+                } else {
+                    Addressing::Labels
+                },
+                stack_policy: [
+                    StackPolicy::Compress,
+                    StackPolicy::Wrap,
+                    StackPolicy::DropNewest,
+                    StackPolicy::Fault,
+                ][policy],
+            };
+            let mut c = decode_genes(&bytes, OPCODES.len() as u8, config);
+            c.add_materials(100);
+            let mut p = Processor::with_config(config);
+            p.start(gene_index);
+            for _i in 0..1000 {
+                let next = p.next_instruction(&c);
+                assert_eq!(p.step(&mut c), next);
+                assert_in_bounds(&p);
+            }
+        }
+    }
 }
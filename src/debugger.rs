@@ -0,0 +1,296 @@
+use std::fmt::{self, Write};
+
+use crate::data::{trimmed_length, Cell, Instr, Processor};
+
+// A condition on the data stack to stop at.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Watch {
+    // the stack holds exactly this many values
+    Depth(usize),
+    // the stack holds more than this many values
+    DepthAbove(usize),
+    // the top of the stack is this value
+    Top(u8),
+}
+
+impl Watch {
+    pub fn matches(&self, data_stack: &[u8]) -> bool {
+        match *self {
+            Watch::Depth(depth) => data_stack.len() == depth,
+            Watch::DepthAbove(depth) => data_stack.len() > depth,
+            Watch::Top(value) => data_stack.last() == Some(&value),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stop {
+    // the next instruction is at a breakpoint
+    Breakpoint { gene_index: u8, pc: usize },
+    // the watch started to match
+    Watch(Watch),
+    // ran the maximum amount of steps without stopping
+    StepLimit,
+}
+
+// Runs a single processor on a cell under control of the user.
+#[derive(Debug, Clone)]
+pub struct Debugger {
+    cell: Cell,
+    processor: Processor,
+    breakpoints: Vec<(u8, usize)>,
+    watches: Vec<Watch>,
+    steps: usize,
+}
+
+impl Debugger {
+    pub fn new(cell: Cell) -> Debugger {
+        let processor = Processor::with_config(cell.config());
+        Debugger {
+            cell,
+            processor,
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+            steps: 0,
+        }
+    }
+
+    pub fn cell(&self) -> &Cell {
+        &self.cell
+    }
+
+    pub fn cell_mut(&mut self) -> &mut Cell {
+        &mut self.cell
+    }
+
+    pub fn processor(&self) -> &Processor {
+        &self.processor
+    }
+
+    pub fn processor_mut(&mut self) -> &mut Processor {
+        &mut self.processor
+    }
+
+    // The amount of instructions executed so far.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn add_breakpoint(&mut self, gene_index: u8, pc: usize) {
+        if !self.breakpoints.contains(&(gene_index, pc)) {
+            self.breakpoints.push((gene_index, pc));
+        }
+    }
+
+    // Returns false if there was no such breakpoint.
+    pub fn remove_breakpoint(&mut self, gene_index: u8, pc: usize) -> bool {
+        let amount = self.breakpoints.len();
+        self.breakpoints.retain(|b| *b != (gene_index, pc));
+        self.breakpoints.len() != amount
+    }
+
+    pub fn breakpoints(&self) -> &[(u8, usize)] {
+        &self.breakpoints
+    }
+
+    pub fn add_watch(&mut self, watch: Watch) {
+        if !self.watches.contains(&watch) {
+            self.watches.push(watch);
+        }
+    }
+
+    pub fn clear_watches(&mut self) {
+        self.watches.clear();
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    pub fn step(&mut self) -> Instr {
+        self.steps += 1;
+        self.processor.step(&mut self.cell)
+    }
+
+    // Run until the next instruction is at a breakpoint or a watch starts
+    // to match. At least one instruction is executed, so we can continue
+    // from a breakpoint.
+    pub fn run(&mut self, max_steps: usize) -> Stop {
+        for _i in 0..max_steps {
+            let before: Vec<bool> = self
+                .watches
+                .iter()
+                .map(|watch| watch.matches(self.processor.data_stack()))
+                .collect();
+            self.step();
+            for (watch, matched) in self.watches.iter().zip(before) {
+                if !matched && watch.matches(self.processor.data_stack()) {
+                    return Stop::Watch(*watch);
+                }
+            }
+            let (gene_index, pc) = self.processor.next_location();
+            if self.breakpoints.contains(&(gene_index, pc)) {
+                return Stop::Breakpoint { gene_index, pc };
+            }
+        }
+        Stop::StepLimit
+    }
+
+    // The state of the processor and the gene it is about to execute, with
+    // the next instruction marked.
+    pub fn view(&self) -> String {
+        let p = &self.processor;
+        let (gene_index, pc) = p.next_location();
+        let mut text = String::new();
+        writeln!(
+            text,
+            "step {} gene {} pc {} loop {} cond {}",
+            self.steps,
+            gene_index,
+            pc,
+            p.loop_start(),
+            p.cond()
+        )
+        .unwrap();
+        writeln!(text, "labels:{}", list(p.labels())).unwrap();
+        writeln!(text, "stack:{}", list(p.data_stack())).unwrap();
+        writeln!(text, "instructions:{}", list(p.instruction_stack())).unwrap();
+        writeln!(text, "calls:").unwrap();
+        for (depth, entry) in p.call_stack().iter().enumerate().rev() {
+            writeln!(
+                text,
+                "  gene {} pc {} loop {} labels:{}",
+                entry.gene_index(),
+                entry.pc(),
+                entry.loop_start(),
+                list(p.labels_at(depth))
+            )
+            .unwrap();
+        }
+        writeln!(text, "== {}", gene_index).unwrap();
+        let gene = self.cell.gene(gene_index);
+        // show the pc, even in the Noops at the end
+        let length = trimmed_length(gene).max(pc + 1);
+        for (i, instr) in gene[..length].iter().enumerate() {
+            let marker = if i == pc { ">" } else { " " };
+            let breakpoint = if self.breakpoints.contains(&(gene_index, i)) {
+                "*"
+            } else {
+                " "
+            };
+            writeln!(text, "{}{}{:3} {}", marker, breakpoint, i, instr).unwrap();
+        }
+        text
+    }
+}
+
+// each value preceded by a space
+fn list<T: fmt::Display>(values: &[T]) -> String {
+    values.iter().map(|value| format!(" {}", value)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn debugger() -> Debugger {
+        let cell = assemble(
+            "
+== 0
+=5
+=1
+Call
+=10
+Add
+
+== 1
+=3
+Add
+",
+        )
+        .unwrap();
+        Debugger::new(cell)
+    }
+
+    #[test]
+    fn test_step() {
+        let mut d = debugger();
+        assert_eq!(d.step(), Instr::Number(5));
+        assert_eq!(d.step(), Instr::Number(1));
+        assert_eq!(d.steps(), 2);
+        assert_eq!(d.processor().data_stack(), &[5, 1]);
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut d = debugger();
+        d.add_breakpoint(1, 1);
+        assert_eq!(
+            d.run(100),
+            Stop::Breakpoint {
+                gene_index: 1,
+                pc: 1
+            }
+        );
+        assert_eq!(d.processor().data_stack(), &[5, 3]);
+        // continuing runs past the breakpoint
+        assert_eq!(d.run(10), Stop::StepLimit);
+        assert!(d.remove_breakpoint(1, 1));
+        assert!(!d.remove_breakpoint(1, 1));
+        assert!(d.breakpoints().is_empty());
+    }
+
+    #[test]
+    fn test_breakpoint_after_return() {
+        let mut d = debugger();
+        d.add_breakpoint(0, 3);
+        assert_eq!(
+            d.run(100),
+            Stop::Breakpoint {
+                gene_index: 0,
+                pc: 3
+            }
+        );
+        assert_eq!(d.processor().data_stack(), &[8]);
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut d = debugger();
+        d.add_watch(Watch::Top(8));
+        assert_eq!(d.run(100), Stop::Watch(Watch::Top(8)));
+        assert_eq!(d.steps(), 5);
+        d.clear_watches();
+        d.add_watch(Watch::DepthAbove(1));
+        assert_eq!(d.run(100), Stop::Watch(Watch::DepthAbove(1)));
+        assert_eq!(d.processor().data_stack(), &[8, 10]);
+    }
+
+    #[test]
+    fn test_watch_only_stops_when_it_starts_to_match() {
+        let mut d = debugger();
+        d.add_watch(Watch::Depth(0));
+        assert_eq!(d.run(3), Stop::StepLimit);
+    }
+
+    #[test]
+    fn test_view() {
+        let mut d = debugger();
+        d.add_breakpoint(1, 1);
+        d.run(100);
+        assert_eq!(
+            d.view(),
+            "step 4 gene 1 pc 1 loop 0 cond true
+labels: 0 0 0 0
+stack: 5 3
+instructions:
+calls:
+  gene 0 pc 3 loop 0 labels: 0 0 0 0
+== 1
+    0 =3
+>*  1 Add
+"
+        );
+    }
+}
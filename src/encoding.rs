@@ -0,0 +1,475 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::data::{trimmed_length, Cell, Instr, VmConfig};
+
+// A cell is stored as a four byte header followed by its instructions:
+//
+// [FORMAT_VERSION, opcode count, gene size, gene amount, instruction bytes...]
+//
+// Each instruction is a single opcode byte, except for Number which is
+// followed by a byte with its value. The instructions fill the genes in
+// order, gene size instructions each. Missing instructions at the end are
+// Noop, so trailing Noops are not stored.
+//
+// Version 1 had no gene size and amount in the header; those genomes get
+// the default dimensions.
+//
+// OPCODES is append-only: new instructions get new opcodes at the end, so
+// the opcodes of saved genomes never change meaning. The opcode count in
+// the header records how many opcodes existed when the genome was saved.
+// Opcode bytes beyond that count wrap around, so any byte string decodes
+// to a valid genome.
+
+pub const FORMAT_VERSION: u8 = 2;
+
+pub const OPCODES: [Instr; 61] = [
+    Instr::Number(0),
+    Instr::Noop,
+    Instr::Add,
+    Instr::Sub,
+    Instr::Mul,
+    Instr::Div,
+    Instr::Eq,
+    Instr::Ne,
+    Instr::Gt,
+    Instr::Lt,
+    Instr::And,
+    Instr::Or,
+    Instr::Not,
+    Instr::Dup,
+    Instr::Drop,
+    Instr::Swap,
+    Instr::Over,
+    Instr::Dup2,
+    Instr::Drop2,
+    Instr::Call,
+    Instr::Return,
+    Instr::Cond,
+    Instr::Label,
+    Instr::Jump,
+    Instr::Read,
+    Instr::Write,
+    Instr::PopInstr,
+    Instr::PushInstr,
+    Instr::WriteGene,
+    Instr::SelectOut,
+    Instr::Out,
+    Instr::OutFull,
+    Instr::SelectIn,
+    Instr::In,
+    Instr::InEmpty,
+    Instr::Rand,
+    Instr::RandRange,
+    Instr::Mod,
+    Instr::Min,
+    Instr::Max,
+    Instr::Inc,
+    Instr::Dec,
+    Instr::BitAnd,
+    Instr::BitOr,
+    Instr::BitXor,
+    Instr::Shl,
+    Instr::Shr,
+    Instr::Rot,
+    Instr::Zero,
+    Instr::SetLoop,
+    Instr::Loop,
+    Instr::Nop0,
+    Instr::Nop1,
+    Instr::JumpRel,
+    Instr::JumpAbs,
+    Instr::OnContact,
+    Instr::OnInput,
+    Instr::SetTimer,
+    Instr::Load,
+    Instr::Store,
+    Instr::TestAndSet,
+];
+
+// When an instruction is treated as a value, the top bit tells numbers
+// from other instructions: the values below NUMBER_VALUES are numbers, the
+// others are opcodes counting down from 255. The split never moves, so a
+// value stays the same instruction when opcodes are added. Genes hold the
+// full range of numbers; only a number of NUMBER_VALUES or more loses its
+// top bit when it's read as a value.
+pub const NUMBER_VALUES: u8 = 128;
+
+// the opcodes besides Number have to fit in the values at the top
+const _: () = assert!(OPCODES.len() - 1 <= 256 - NUMBER_VALUES as usize);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    MissingHeader,
+    UnsupportedVersion(u8),
+    UnknownOpcodes(u8),
+    BadDimensions { gene_size: u8, gene_amount: u8 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::MissingHeader => write!(f, "genome has no header"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported genome format version {}", version)
+            }
+            DecodeError::UnknownOpcodes(count) => write!(
+                f,
+                "genome uses {} opcodes, only {} are known",
+                count,
+                OPCODES.len()
+            ),
+            DecodeError::BadDimensions {
+                gene_size,
+                gene_amount,
+            } => write!(f, "genome has {} genes of size {}", gene_amount, gene_size),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl Instr {
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            Instr::Number(_) => 0,
+            Instr::Noop => 1,
+            Instr::Add => 2,
+            Instr::Sub => 3,
+            Instr::Mul => 4,
+            Instr::Div => 5,
+            Instr::Eq => 6,
+            Instr::Ne => 7,
+            Instr::Gt => 8,
+            Instr::Lt => 9,
+            Instr::And => 10,
+            Instr::Or => 11,
+            Instr::Not => 12,
+            Instr::Dup => 13,
+            Instr::Drop => 14,
+            Instr::Swap => 15,
+            Instr::Over => 16,
+            Instr::Dup2 => 17,
+            Instr::Drop2 => 18,
+            Instr::Call => 19,
+            Instr::Return => 20,
+            Instr::Cond => 21,
+            Instr::Label => 22,
+            Instr::Jump => 23,
+            Instr::Read => 24,
+            Instr::Write => 25,
+            Instr::PopInstr => 26,
+            Instr::PushInstr => 27,
+            Instr::WriteGene => 28,
+            Instr::SelectOut => 29,
+            Instr::Out => 30,
+            Instr::OutFull => 31,
+            Instr::SelectIn => 32,
+            Instr::In => 33,
+            Instr::InEmpty => 34,
+            Instr::Rand => 35,
+            Instr::RandRange => 36,
+            Instr::Mod => 37,
+            Instr::Min => 38,
+            Instr::Max => 39,
+            Instr::Inc => 40,
+            Instr::Dec => 41,
+            Instr::BitAnd => 42,
+            Instr::BitOr => 43,
+            Instr::BitXor => 44,
+            Instr::Shl => 45,
+            Instr::Shr => 46,
+            Instr::Rot => 47,
+            Instr::Zero => 48,
+            Instr::SetLoop => 49,
+            Instr::Loop => 50,
+            Instr::Nop0 => 51,
+            Instr::Nop1 => 52,
+            Instr::JumpRel => 53,
+            Instr::JumpAbs => 54,
+            Instr::OnContact => 55,
+            Instr::OnInput => 56,
+            Instr::SetTimer => 57,
+            Instr::Load => 58,
+            Instr::Store => 59,
+            Instr::TestAndSet => 60,
+        }
+    }
+
+    // An instruction as a single value for the data stack, see
+    // NUMBER_VALUES. Numbers too big for a value keep their low bits.
+    pub fn to_value(self) -> u8 {
+        match self {
+            Instr::Number(n) => n % NUMBER_VALUES,
+            _ => (256 - self.opcode() as usize) as u8,
+        }
+    }
+
+    // The values between the numbers and the opcodes are kept for opcodes
+    // to come; until then they are Noop.
+    pub fn from_value(value: u8) -> Instr {
+        if value < NUMBER_VALUES {
+            return Instr::Number(value);
+        }
+        let opcode = 256 - value as usize;
+        if opcode < OPCODES.len() {
+            OPCODES[opcode]
+        } else {
+            Instr::Noop
+        }
+    }
+
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.push(self.opcode());
+        if let Instr::Number(n) = *self {
+            bytes.push(n);
+        }
+    }
+}
+
+impl Cell {
+    // Only the gene size and amount of the config are stored.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![
+            FORMAT_VERSION,
+            OPCODES.len() as u8,
+            self.gene_size() as u8,
+            self.gene_amount() as u8,
+        ];
+        let mut instructions = Vec::with_capacity(self.gene_size() * self.gene_amount());
+        for gene_index in 0..self.gene_amount() {
+            instructions.extend_from_slice(self.gene(gene_index as u8));
+        }
+        for instr in &instructions[..trimmed_length(&instructions)] {
+            instr.encode(&mut bytes);
+        }
+        bytes
+    }
+
+    // The rest of the config is the default.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Cell, DecodeError> {
+        let mut config = VmConfig::default();
+        let header_size = match bytes.first() {
+            None => return Err(DecodeError::MissingHeader),
+            Some(1) => 2,
+            Some(2) => 4,
+            Some(version) => return Err(DecodeError::UnsupportedVersion(*version)),
+        };
+        if bytes.len() < header_size {
+            return Err(DecodeError::MissingHeader);
+        }
+        let opcode_count = bytes[1];
+        if opcode_count == 0 || opcode_count as usize > OPCODES.len() {
+            return Err(DecodeError::UnknownOpcodes(opcode_count));
+        }
+        if header_size == 4 {
+            config.gene_size = bytes[2] as usize;
+            config.gene_amount = bytes[3] as usize;
+            if !config.is_valid() {
+                return Err(DecodeError::BadDimensions {
+                    gene_size: bytes[2],
+                    gene_amount: bytes[3],
+                });
+            }
+        }
+        Ok(decode_genes(&bytes[header_size..], opcode_count, config))
+    }
+}
+
+// Decodes instruction bytes without a header. This never fails: every byte
+// string is a genome.
+pub fn decode_genes(bytes: &[u8], opcode_count: u8, config: VmConfig) -> Cell {
+    let mut cell = Cell::with_config(config);
+    let mut bytes = bytes.iter();
+    for gene_index in 0..config.gene_amount {
+        let mut instructions = Vec::with_capacity(config.gene_size);
+        while instructions.len() < config.gene_size {
+            let opcode = match bytes.next() {
+                Some(opcode) => *opcode,
+                None => break,
+            };
+            let instr = match OPCODES[(opcode % opcode_count) as usize] {
+                // a missing value at the very end is 0
+                Instr::Number(_) => Instr::Number(bytes.next().copied().unwrap_or(0)),
+                instr => instr,
+            };
+            instructions.push(instr);
+        }
+        cell.set_gene(gene_index as u8, instructions);
+    }
+    cell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{GENE_AMOUNT, GENE_SIZE};
+
+    #[test]
+    fn test_opcodes_match_table() {
+        for (opcode, instr) in OPCODES.iter().enumerate() {
+            assert_eq!(instr.opcode() as usize, opcode);
+        }
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(Instr::Noop.to_value(), 255);
+        assert_eq!(Instr::Add.to_value(), 254);
+        assert_eq!(Instr::Number(5).to_value(), 5);
+        assert_eq!(Instr::Number(200).to_value(), 200 - NUMBER_VALUES);
+        for n in 0..NUMBER_VALUES {
+            assert_eq!(Instr::Number(n).to_value(), n);
+            assert_eq!(Instr::from_value(n), Instr::Number(n));
+        }
+        for instr in OPCODES.iter().skip(1) {
+            assert!(instr.to_value() >= NUMBER_VALUES);
+            assert_eq!(Instr::from_value(instr.to_value()), *instr);
+        }
+    }
+
+    #[test]
+    fn test_values_reserved() {
+        let first_opcode_value = (256 - (OPCODES.len() - 1)) as u8;
+        for value in 0..=255 {
+            let instr = Instr::from_value(value);
+            if (NUMBER_VALUES..first_opcode_value).contains(&value) {
+                assert_eq!(instr, Instr::Noop);
+            } else {
+                assert_eq!(instr.to_value(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_instr() {
+        let mut bytes = Vec::new();
+        Instr::Add.encode(&mut bytes);
+        Instr::Number(200).encode(&mut bytes);
+        assert_eq!(bytes, vec![2, 0, 200]);
+    }
+
+    #[test]
+    fn test_empty_cell_to_bytes() {
+        assert_eq!(
+            Cell::new().to_bytes(),
+            vec![
+                FORMAT_VERSION,
+                OPCODES.len() as u8,
+                GENE_SIZE as u8,
+                GENE_AMOUNT as u8
+            ]
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut cell = Cell::new();
+        cell.set_gene(
+            0,
+            vec![Instr::Number(5), Instr::Number(1), Instr::Call, Instr::Noop],
+        );
+        cell.set_gene(1, vec![Instr::Number(255), Instr::Add, Instr::Return]);
+        cell.set_gene(15, vec![Instr::Number(0); GENE_SIZE]);
+        assert_eq!(Cell::from_bytes(&cell.to_bytes()), Ok(cell));
+    }
+
+    #[test]
+    fn test_round_trip_every_number() {
+        let mut cell = Cell::new();
+        for n in 0..=255u8 {
+            let mut bytes = Vec::new();
+            Instr::Number(n).encode(&mut bytes);
+            let decoded = decode_genes(&bytes, OPCODES.len() as u8, VmConfig::default());
+            assert_eq!(decoded.gene(0)[0], Instr::Number(n));
+            let gene_index = n as usize / GENE_SIZE;
+            cell.gene_mut(gene_index as u8)[n as usize % GENE_SIZE] = Instr::Number(n);
+        }
+        assert_eq!(Cell::from_bytes(&cell.to_bytes()), Ok(cell));
+    }
+
+    #[test]
+    fn test_round_trip_config() {
+        let config = VmConfig {
+            gene_size: 5,
+            gene_amount: 3,
+            ..VmConfig::default()
+        };
+        let mut cell = Cell::with_config(config);
+        cell.set_gene(0, vec![Instr::Number(1); 5]);
+        cell.set_gene(2, vec![Instr::Dup, Instr::Add]);
+        let bytes = cell.to_bytes();
+        assert_eq!(&bytes[2..4], &[5, 3]);
+        assert_eq!(Cell::from_bytes(&bytes), Ok(cell));
+    }
+
+    #[test]
+    fn test_from_bytes_header_errors() {
+        assert_eq!(Cell::from_bytes(&[]), Err(DecodeError::MissingHeader));
+        assert_eq!(
+            Cell::from_bytes(&[FORMAT_VERSION, 1]),
+            Err(DecodeError::MissingHeader)
+        );
+        assert_eq!(
+            Cell::from_bytes(&[FORMAT_VERSION + 1, 1]),
+            Err(DecodeError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+        assert_eq!(
+            Cell::from_bytes(&[FORMAT_VERSION, 0, 32, 16]),
+            Err(DecodeError::UnknownOpcodes(0))
+        );
+        assert_eq!(
+            Cell::from_bytes(&[FORMAT_VERSION, 255, 32, 16]),
+            Err(DecodeError::UnknownOpcodes(255))
+        );
+        assert_eq!(
+            Cell::from_bytes(&[FORMAT_VERSION, 1, 0, 16]),
+            Err(DecodeError::BadDimensions {
+                gene_size: 0,
+                gene_amount: 16
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_bytes_older_opcode_count() {
+        // saved in version 1 when there were only 3 opcodes; 3 wraps
+        // around to Number
+        let cell = Cell::from_bytes(&[1, 3, 2, 3, 7]).unwrap();
+        assert_eq!(cell.config(), VmConfig::default());
+        assert_eq!(cell.gene(0)[0], Instr::Add);
+        assert_eq!(cell.gene(0)[1], Instr::Number(7));
+        assert_eq!(cell.gene(0)[2], Instr::Noop);
+    }
+
+    #[test]
+    fn test_decode_any_bytes() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let cell = decode_genes(&bytes, OPCODES.len() as u8, VmConfig::default());
+        assert_eq!(cell.gene(0)[0], Instr::Number(1));
+        assert_eq!(cell.gene(0)[1], Instr::Add);
+        // the byte after the last opcode wraps around to Number again
+        let last = OPCODES.len();
+        let gene = cell.gene(((last - 1) / GENE_SIZE) as u8);
+        assert_eq!(gene[(last - 1) % GENE_SIZE], Instr::Number(last as u8 + 1));
+    }
+
+    #[test]
+    fn test_decode_big_number() {
+        let cell = decode_genes(&[0, 200], OPCODES.len() as u8, VmConfig::default());
+        assert_eq!(cell.gene(0)[0], Instr::Number(200));
+    }
+
+    #[test]
+    fn test_decode_truncated_number() {
+        let cell = decode_genes(&[0], OPCODES.len() as u8, VmConfig::default());
+        assert_eq!(cell.gene(0)[0], Instr::Number(0));
+    }
+
+    #[test]
+    fn test_decode_too_many_bytes() {
+        let bytes = vec![Instr::Dup.opcode(); GENE_SIZE * GENE_AMOUNT + 10];
+        let cell = decode_genes(&bytes, OPCODES.len() as u8, VmConfig::default());
+        assert_eq!(cell.gene(GENE_AMOUNT as u8 - 1), &[Instr::Dup; GENE_SIZE]);
+    }
+}
@@ -0,0 +1,13 @@
+pub mod assembler;
+pub mod batch;
+pub mod compiled;
+pub mod data;
+pub mod debugger;
+pub mod encoding;
+pub mod mutation;
+pub mod ports;
+pub mod recombination;
+pub mod runtime;
+pub mod statistics;
+pub mod trace;
+pub mod vmplugin;
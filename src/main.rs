@@ -2,15 +2,17 @@ use std::convert::TryInto;
 
 use bevy::prelude::*;
 use bevy_prototype_lyon::prelude::ShapePlugin;
-mod data;
 mod renderplugin;
 use bevy_rapier2d::physics::{
     ColliderHandleComponent, EventQueue, JointBuilderComponent, RapierConfiguration,
     RapierPhysicsPlugin, RigidBodyHandleComponent,
 };
 use bevy_rapier2d::rapier::dynamics::{RigidBodyBuilder, RigidBodySet};
-use bevy_rapier2d::rapier::geometry::{ColliderBuilder, ColliderSet};
-use data::{Cell, Instr, Processor};
+use bevy_rapier2d::rapier::geometry::{Collider, ColliderBuilder, ColliderSet};
+use caldo_bevy::assembler::load;
+use caldo_bevy::data::{Instr, Processor, PORT_AMOUNT};
+use caldo_bevy::runtime::{CellRuntime, TickReport};
+use caldo_bevy::vmplugin::{Ports, VmPlugin, WorldSeed};
 use na::{Point2, Rotation2, Vector2};
 use nalgebra as na;
 
@@ -24,6 +26,12 @@ struct Thruster {
     on: bool,
 }
 
+// A value on this output port points the thruster to a side.
+const THRUSTER_SIDE_PORT: usize = 0;
+// A value on this output port turns the thruster off when it's 0, on
+// otherwise.
+const THRUSTER_ON_PORT: usize = 1;
+
 fn regular_polygon(sides: usize, radius: f32) -> Vec<Point2<f32>> {
     use std::f32::consts::PI;
     let n = sides as f32;
@@ -42,7 +50,7 @@ fn regular_polygon(sides: usize, radius: f32) -> Vec<Point2<f32>> {
     points
 }
 
-fn setup_physics(commands: &mut Commands) {
+fn setup_physics(commands: &mut Commands, mut world_seed: ResMut<WorldSeed>) {
     // Static rigid-body with a cuboid shape.
     let rigid_body1 = RigidBodyBuilder::new_static().rotation(0.2);
     let collider1 = ColliderBuilder::cuboid(10.0, 1.0);
@@ -95,12 +103,19 @@ fn setup_physics(commands: &mut Commands) {
 
     let mut rng = rand::thread_rng();
 
+    let genome = load(concat!(env!("CARGO_MANIFEST_DIR"), "/genomes/replicator.txt"))
+        .expect("genomes/replicator.txt should assemble");
+
     iter.for_each(|item| {
         let body = RigidBodyBuilder::new_dynamic().translation(
             rng.gen::<f32>() * 50.0 - 25.0,
             rng.gen::<f32>() * 50.0 - 25.0,
         );
         let collider = ColliderBuilder::convex_hull(&points).unwrap();
+        let mut cell = genome.clone();
+        cell.add_materials(1000);
+        let mut runtime = CellRuntime::new(cell);
+        runtime.set_seed(world_seed.next_seed());
         commands.spawn((
             body,
             collider,
@@ -108,6 +123,9 @@ fn setup_physics(commands: &mut Commands) {
                 side: rng.gen_range(0..6),
                 on: true,
             },
+            runtime,
+            TickReport::default(),
+            Ports::default(),
         ));
     })
 
@@ -149,15 +167,20 @@ fn setup_user_data(
     }
 }
 
-fn display_events(colliders: Res<ColliderSet>, events: Res<EventQueue>) {
+// Tell the cells on both sides of a contact where they were touched.
+fn contact_system(
+    colliders: Res<ColliderSet>,
+    events: Res<EventQueue>,
+    mut runtimes: Query<&mut CellRuntime>,
+) {
     while let Ok(contact_event) = events.contact_events.pop() {
         match contact_event {
             ContactEvent::Started(first_handle, second_handle) => {
-                if let Some(collider) = colliders.get(first_handle) {
-                    println!("Received contact event");
-                    let first_entity = Entity::from_bits(collider.user_data as u64);
-                    println!("User data {:?}", collider.user_data);
-                    println!("First entity: {:?}", first_entity);
+                if let (Some(first), Some(second)) =
+                    (colliders.get(first_handle), colliders.get(second_handle))
+                {
+                    contact(&mut runtimes, first, second);
+                    contact(&mut runtimes, second, first);
                 }
             }
             _ => {}
@@ -165,6 +188,16 @@ fn display_events(colliders: Res<ColliderSet>, events: Res<EventQueue>) {
     }
 }
 
+fn contact(runtimes: &mut Query<&mut CellRuntime>, collider: &Collider, other: &Collider) {
+    let entity = Entity::from_bits(collider.user_data as u64);
+    // not everything is a cell
+    if let Ok(mut runtime) = runtimes.get_mut(entity) {
+        let position = collider.position();
+        let direction = other.position().translation.vector - position.translation.vector;
+        runtime.contact(side_for_vector(6, position.inverse_transform_vector(&direction)));
+    }
+}
+
 fn setup_graphics(commands: &mut Commands, mut configuration: ResMut<RapierConfiguration>) {
     configuration.scale = 10.0;
     // not sure why these two need to be configured
@@ -179,11 +212,27 @@ fn setup_graphics(commands: &mut Commands, mut configuration: ResMut<RapierConfi
         });
 }
 
+// Take what the cells said on their output ports. The thruster ports
+// steer the thruster; everything else isn't used yet and is dropped.
+fn output_system(mut query: Query<(&mut Thruster, &mut Ports)>) {
+    for (mut thruster, mut ports) in query.iter_mut() {
+        while let Some(value) = ports.outputs[THRUSTER_SIDE_PORT].pop() {
+            thruster.side = value % 6;
+        }
+        while let Some(value) = ports.outputs[THRUSTER_ON_PORT].pop() {
+            thruster.on = value != 0;
+        }
+        for port in 0..PORT_AMOUNT {
+            while ports.outputs[port].pop().is_some() {}
+        }
+    }
+}
+
 fn thruster_system(
     mut bodies: ResMut<RigidBodySet>,
     query: Query<(&RigidBodyHandleComponent, &Thruster)>,
 ) {
-    for (rigid_body_handle, thruster) in query.iter() {
+    for (rigid_body_handle, thruster) in query.iter().filter(|(_, thruster)| thruster.on) {
         let body = bodies.get_mut(rigid_body_handle.handle()).unwrap();
         let t = body.position();
 
@@ -202,6 +251,15 @@ fn vector_for_side(sides: u8, s: u8) -> Vector2<f32> {
     radian_to_vector(((2. * PI) / (sides as f32)) * (s as f32) - 0.5 * PI)
 }
 
+// The side a vector in the frame of the cell points to, the inverse of
+// vector_for_side.
+fn side_for_vector(sides: u8, v: Vector2<f32>) -> u8 {
+    use std::f32::consts::PI;
+    let r = (-v.y).atan2(v.x);
+    let side = ((r + 0.5 * PI) / ((2. * PI) / (sides as f32))).round() as i32;
+    side.rem_euclid(sides as i32) as u8
+}
+
 fn radian_to_vector(r: f32) -> Vector2<f32> {
     // not sure why I have to flip the y coordinate
     Vector2::new(r.cos(), -r.sin())
@@ -229,6 +287,8 @@ fn main() {
         .add_plugin(RapierPhysicsPlugin)
         // our own render plugin, based on Rapier's for now
         .add_plugin(renderplugin::RapierRenderPlugin)
+        // run the cells, in a stage before thruster_system
+        .add_plugin(VmPlugin)
         .add_resource(RapierConfiguration {
             gravity: Vector::new(0.0, 0.0),
             ..Default::default()
@@ -238,8 +298,9 @@ fn main() {
         // setup physics
         .add_startup_system(setup_physics.system())
         .add_startup_system_to_stage(bevy::app::startup_stage::POST_STARTUP, setup_user_data.system())
+        .add_system(output_system.system())
         .add_system(thruster_system.system())
-        .add_system(display_events.system())
+        .add_system(contact_system.system())
         .run();
 }
 
@@ -297,4 +358,14 @@ mod tests {
         assert_float_absolute_eq!(v5.x, -0.8660254);
         assert_float_absolute_eq!(v5.y, 0.5);
     }
+
+    #[test]
+    fn test_side_for_vector() {
+        for side in 0..6 {
+            assert_eq!(side_for_vector(6, vector_for_side(6, side)), side);
+        }
+        // in between sides 0 and 1, but closer to 1
+        assert_eq!(side_for_vector(6, Vector2::new(0.6, 0.4)), 1);
+        assert_eq!(side_for_vector(6, Vector2::new(-0.1, 1.0)), 0);
+    }
 }
@@ -0,0 +1,472 @@
+use rand::Rng;
+
+use crate::data::{Cell, Instr};
+use crate::encoding::OPCODES;
+
+// Rates are probabilities between 0.0 and 1.0. Rates outside that range
+// are clamped to it, and NaN is taken as 0.0.
+// substitution and number_tweak apply to each instruction, insertion,
+// deletion and duplication to each gene.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct MutationRates {
+    pub substitution: f64,
+    pub insertion: f64,
+    pub deletion: f64,
+    pub duplication: f64,
+    pub number_tweak: f64,
+    // largest amount a Number constant is changed by in a tweak; more than
+    // 127 counts as 127
+    pub max_number_delta: u8,
+}
+
+// A record of a single mutation, so we can log what changed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    Substitution {
+        gene_index: u8,
+        pc: usize,
+        old: Instr,
+        new: Instr,
+    },
+    // the last instruction of the gene is shifted out and lost
+    Insertion {
+        gene_index: u8,
+        pc: usize,
+        instr: Instr,
+        lost: Instr,
+    },
+    // a Noop is shifted in at the end of the gene
+    Deletion {
+        gene_index: u8,
+        pc: usize,
+        instr: Instr,
+    },
+    // the target gene is overwritten
+    Duplication {
+        from_gene_index: u8,
+        to_gene_index: u8,
+    },
+    NumberTweak {
+        gene_index: u8,
+        pc: usize,
+        old: u8,
+        new: u8,
+    },
+}
+
+pub fn random_instr<R: Rng>(rng: &mut R) -> Instr {
+    match OPCODES[rng.gen_range(0..OPCODES.len())] {
+        Instr::Number(_) => Instr::Number(rng.gen()),
+        instr => instr,
+    }
+}
+
+// Gene indexes and positions wrap around, like in Cell::gene. The records
+// hold the wrapped ones.
+fn wrap(cell: &Cell, gene_index: u8, pc: usize) -> (u8, usize) {
+    (
+        (gene_index as usize % cell.gene_amount()) as u8,
+        pc % cell.gene_size(),
+    )
+}
+
+pub fn substitute(cell: &mut Cell, gene_index: u8, pc: usize, instr: Instr) -> Mutation {
+    let (gene_index, pc) = wrap(cell, gene_index, pc);
+    let gene = cell.gene_mut(gene_index);
+    let old = gene[pc];
+    gene[pc] = instr;
+    Mutation::Substitution {
+        gene_index,
+        pc,
+        old,
+        new: instr,
+    }
+}
+
+pub fn insert(cell: &mut Cell, gene_index: u8, pc: usize, instr: Instr) -> Mutation {
+    let (gene_index, pc) = wrap(cell, gene_index, pc);
+    let gene = cell.gene_mut(gene_index);
+    let lost = gene[gene.len() - 1];
+    gene[pc..].rotate_right(1);
+    gene[pc] = instr;
+    Mutation::Insertion {
+        gene_index,
+        pc,
+        instr,
+        lost,
+    }
+}
+
+pub fn delete(cell: &mut Cell, gene_index: u8, pc: usize) -> Mutation {
+    let (gene_index, pc) = wrap(cell, gene_index, pc);
+    let gene = cell.gene_mut(gene_index);
+    let instr = gene[pc];
+    gene[pc..].rotate_left(1);
+    let last = gene.len() - 1;
+    gene[last] = Instr::Noop;
+    Mutation::Deletion {
+        gene_index,
+        pc,
+        instr,
+    }
+}
+
+pub fn duplicate(cell: &mut Cell, from_gene_index: u8, to_gene_index: u8) -> Mutation {
+    let (from_gene_index, _) = wrap(cell, from_gene_index, 0);
+    let (to_gene_index, _) = wrap(cell, to_gene_index, 0);
+    let gene = cell.gene(from_gene_index).to_vec();
+    cell.gene_mut(to_gene_index).copy_from_slice(&gene);
+    Mutation::Duplication {
+        from_gene_index,
+        to_gene_index,
+    }
+}
+
+// Returns None if there is no Number at this position.
+pub fn tweak_number(cell: &mut Cell, gene_index: u8, pc: usize, delta: i8) -> Option<Mutation> {
+    let (gene_index, pc) = wrap(cell, gene_index, pc);
+    let gene = cell.gene_mut(gene_index);
+    match gene[pc] {
+        Instr::Number(old) => {
+            let new = old.wrapping_add(delta as u8);
+            gene[pc] = Instr::Number(new);
+            Some(Mutation::NumberTweak {
+                gene_index,
+                pc,
+                old,
+                new,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn chance<R: Rng>(rng: &mut R, rate: f64) -> bool {
+    // gen_bool panics outside of 0.0 to 1.0
+    let rate = if rate.is_nan() {
+        0.0
+    } else {
+        rate.clamp(0.0, 1.0)
+    };
+    rng.gen_bool(rate)
+}
+
+// Mutate the cell in place with the given rates. The mutations are
+// returned in the order they were applied.
+pub fn mutate<R: Rng>(cell: &mut Cell, rates: &MutationRates, rng: &mut R) -> Vec<Mutation> {
+    let gene_size = cell.gene_size();
+    let gene_amount = cell.gene_amount();
+    let mut mutations = Vec::new();
+    for gene_index in 0..gene_amount as u8 {
+        for pc in 0..gene_size {
+            if chance(rng, rates.substitution) {
+                let instr = random_instr(rng);
+                mutations.push(substitute(cell, gene_index, pc, instr));
+            } else if rates.max_number_delta > 0 && chance(rng, rates.number_tweak) {
+                // a bigger delta doesn't fit in an i8
+                let max_delta = rates.max_number_delta.min(i8::MAX as u8);
+                let magnitude = rng.gen_range(1..=max_delta) as i8;
+                let delta = if rng.gen() { magnitude } else { -magnitude };
+                if let Some(mutation) = tweak_number(cell, gene_index, pc, delta) {
+                    mutations.push(mutation);
+                }
+            }
+        }
+        if chance(rng, rates.insertion) {
+            let pc = rng.gen_range(0..gene_size);
+            let instr = random_instr(rng);
+            mutations.push(insert(cell, gene_index, pc, instr));
+        }
+        if chance(rng, rates.deletion) {
+            let pc = rng.gen_range(0..gene_size);
+            mutations.push(delete(cell, gene_index, pc));
+        }
+    }
+    // with a single gene there is nothing to duplicate to
+    for from_gene_index in 0..gene_amount as u8 {
+        if gene_amount > 1 && chance(rng, rates.duplication) {
+            // pick any gene except the source itself
+            let offset = rng.gen_range(1..gene_amount);
+            let to_gene_index = ((from_gene_index as usize + offset) % gene_amount) as u8;
+            mutations.push(duplicate(cell, from_gene_index, to_gene_index));
+        }
+    }
+    mutations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{GENE_AMOUNT, GENE_SIZE};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn sample_cell() -> Cell {
+        let mut c = Cell::new();
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(5),
+                Instr::Number(1),
+                Instr::Call,
+                Instr::Number(10),
+                Instr::Add,
+            ],
+        );
+        c.set_gene(1, vec![Instr::Number(3), Instr::Add, Instr::Return]);
+        c
+    }
+
+    #[test]
+    fn test_substitute() {
+        let mut c = sample_cell();
+        let m = substitute(&mut c, 0, 2, Instr::Dup);
+        assert_eq!(c.gene(0)[2], Instr::Dup);
+        assert_eq!(
+            m,
+            Mutation::Substitution {
+                gene_index: 0,
+                pc: 2,
+                old: Instr::Call,
+                new: Instr::Dup
+            }
+        );
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut c = sample_cell();
+        c.gene_mut(1)[GENE_SIZE - 1] = Instr::Jump;
+        let m = insert(&mut c, 1, 1, Instr::Dup);
+        assert_eq!(
+            &c.gene(1)[..5],
+            &[
+                Instr::Number(3),
+                Instr::Dup,
+                Instr::Add,
+                Instr::Return,
+                Instr::Noop
+            ]
+        );
+        assert_eq!(
+            m,
+            Mutation::Insertion {
+                gene_index: 1,
+                pc: 1,
+                instr: Instr::Dup,
+                lost: Instr::Jump
+            }
+        );
+    }
+
+    #[test]
+    fn test_insert_at_end() {
+        let mut c = sample_cell();
+        insert(&mut c, 1, GENE_SIZE - 1, Instr::Dup);
+        assert_eq!(c.gene(1)[GENE_SIZE - 1], Instr::Dup);
+        assert_eq!(c.gene(1)[2], Instr::Return);
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut c = sample_cell();
+        c.gene_mut(1)[GENE_SIZE - 1] = Instr::Jump;
+        let m = delete(&mut c, 1, 0);
+        assert_eq!(&c.gene(1)[..3], &[Instr::Add, Instr::Return, Instr::Noop]);
+        assert_eq!(c.gene(1)[GENE_SIZE - 2], Instr::Jump);
+        assert_eq!(c.gene(1)[GENE_SIZE - 1], Instr::Noop);
+        assert_eq!(
+            m,
+            Mutation::Deletion {
+                gene_index: 1,
+                pc: 0,
+                instr: Instr::Number(3)
+            }
+        );
+    }
+
+    #[test]
+    fn test_duplicate() {
+        let mut c = sample_cell();
+        duplicate(&mut c, 1, 7);
+        assert_eq!(c.gene(7), c.gene(1));
+    }
+
+    #[test]
+    fn test_tweak_number() {
+        let mut c = sample_cell();
+        let m = tweak_number(&mut c, 0, 0, -6);
+        assert_eq!(c.gene(0)[0], Instr::Number(255));
+        assert_eq!(
+            m,
+            Some(Mutation::NumberTweak {
+                gene_index: 0,
+                pc: 0,
+                old: 5,
+                new: 255
+            })
+        );
+    }
+
+    #[test]
+    fn test_positions_wrap() {
+        let mut c = sample_cell();
+        let m = substitute(&mut c, GENE_AMOUNT as u8, GENE_SIZE + 2, Instr::Dup);
+        assert_eq!(c.gene(0)[2], Instr::Dup);
+        assert_eq!(
+            m,
+            Mutation::Substitution {
+                gene_index: 0,
+                pc: 2,
+                old: Instr::Call,
+                new: Instr::Dup
+            }
+        );
+        insert(&mut c, 1, GENE_SIZE, Instr::Over);
+        assert_eq!(c.gene(1)[0], Instr::Over);
+        delete(&mut c, 1, GENE_SIZE * 3);
+        assert_eq!(c.gene(1)[0], Instr::Number(3));
+        assert!(tweak_number(&mut c, 0, GENE_SIZE, 1).is_some());
+        assert_eq!(c.gene(0)[0], Instr::Number(6));
+        let m = duplicate(&mut c, GENE_AMOUNT as u8 + 1, 7);
+        assert_eq!(
+            m,
+            Mutation::Duplication {
+                from_gene_index: 1,
+                to_gene_index: 7
+            }
+        );
+        assert_eq!(c.gene(7), c.gene(1));
+    }
+
+    #[test]
+    fn test_tweak_not_a_number() {
+        let mut c = sample_cell();
+        assert_eq!(tweak_number(&mut c, 0, 2, 1), None);
+        assert_eq!(c, sample_cell());
+    }
+
+    #[test]
+    fn test_mutate_zero_rates() {
+        let mut c = sample_cell();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mutations = mutate(&mut c, &MutationRates::default(), &mut rng);
+        assert!(mutations.is_empty());
+        assert_eq!(c, sample_cell());
+    }
+
+    #[test]
+    fn test_mutate_out_of_range_rates() {
+        let rates = MutationRates {
+            substitution: 2.0,
+            insertion: -1.0,
+            deletion: f64::NAN,
+            duplication: f64::NEG_INFINITY,
+            number_tweak: f64::INFINITY,
+            max_number_delta: 1,
+        };
+        let mut c = Cell::new();
+        let mutations = mutate(&mut c, &rates, &mut StdRng::seed_from_u64(0));
+        assert_eq!(mutations.len(), GENE_SIZE * GENE_AMOUNT);
+        assert!(mutations
+            .iter()
+            .all(|mutation| matches!(mutation, Mutation::Substitution { .. })));
+    }
+
+    #[test]
+    fn test_mutate_deterministic() {
+        let rates = MutationRates {
+            substitution: 0.01,
+            insertion: 0.1,
+            deletion: 0.1,
+            duplication: 0.05,
+            number_tweak: 0.2,
+            max_number_delta: 3,
+        };
+        let mut a = sample_cell();
+        let mut b = sample_cell();
+        let mutations_a = mutate(&mut a, &rates, &mut StdRng::seed_from_u64(42));
+        let mutations_b = mutate(&mut b, &rates, &mut StdRng::seed_from_u64(42));
+        assert!(!mutations_a.is_empty());
+        assert_eq!(mutations_a, mutations_b);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_mutate_substitution_everywhere() {
+        let rates = MutationRates {
+            substitution: 1.0,
+            ..MutationRates::default()
+        };
+        let mut c = Cell::new();
+        let mutations = mutate(&mut c, &rates, &mut StdRng::seed_from_u64(1));
+        assert_eq!(mutations.len(), GENE_SIZE * GENE_AMOUNT);
+    }
+
+    #[test]
+    fn test_mutate_number_tweak_within_delta() {
+        let rates = MutationRates {
+            number_tweak: 1.0,
+            max_number_delta: 2,
+            ..MutationRates::default()
+        };
+        let mut c = sample_cell();
+        let mutations = mutate(&mut c, &rates, &mut StdRng::seed_from_u64(3));
+        // there are four numbers in the sample cell
+        assert_eq!(mutations.len(), 4);
+        for mutation in mutations {
+            match mutation {
+                Mutation::NumberTweak { old, new, .. } => {
+                    let delta = new.wrapping_sub(old) as i8;
+                    assert!(delta != 0 && delta.abs() <= 2);
+                }
+                other => panic!("unexpected mutation {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_mutate_largest_number_delta() {
+        let rates = MutationRates {
+            number_tweak: 1.0,
+            max_number_delta: u8::MAX,
+            ..MutationRates::default()
+        };
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Number(0); GENE_SIZE]);
+        let mutations = mutate(&mut c, &rates, &mut StdRng::seed_from_u64(3));
+        assert_eq!(mutations.len(), GENE_SIZE);
+        let deltas: Vec<i8> = mutations
+            .iter()
+            .map(|mutation| match mutation {
+                Mutation::NumberTweak { old, new, .. } => new.wrapping_sub(*old) as i8,
+                other => panic!("unexpected mutation {:?}", other),
+            })
+            .collect();
+        assert!(deltas.iter().all(|delta| *delta != 0 && *delta != i8::MIN));
+        // the whole range up to 127 is used
+        assert!(deltas.iter().any(|delta| delta.abs() > 64));
+        assert!(deltas.iter().any(|delta| *delta > 0));
+        assert!(deltas.iter().any(|delta| *delta < 0));
+    }
+
+    #[test]
+    fn test_mutate_duplication_other_gene() {
+        let rates = MutationRates {
+            duplication: 1.0,
+            ..MutationRates::default()
+        };
+        let mut c = sample_cell();
+        let mutations = mutate(&mut c, &rates, &mut StdRng::seed_from_u64(5));
+        assert_eq!(mutations.len(), GENE_AMOUNT);
+        for mutation in mutations {
+            match mutation {
+                Mutation::Duplication {
+                    from_gene_index,
+                    to_gene_index,
+                } => assert_ne!(from_gene_index, to_gene_index),
+                other => panic!("unexpected mutation {:?}", other),
+            }
+        }
+    }
+}
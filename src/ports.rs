@@ -0,0 +1,221 @@
+use crate::data::{Event, Processor, PORT_AMOUNT};
+
+pub const PORT_QUEUE_SIZE: usize = 8;
+
+// A bounded first-in first-out queue of values for a port.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PortQueue {
+    values: [u8; PORT_QUEUE_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl PortQueue {
+    pub fn new() -> PortQueue {
+        PortQueue {
+            values: [0; PORT_QUEUE_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == PORT_QUEUE_SIZE
+    }
+
+    // Returns false if the queue is full; the value is dropped.
+    pub fn push(&mut self, value: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.values[(self.start + self.len) % PORT_QUEUE_SIZE] = value;
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let value = self.values[self.start];
+        self.start = (self.start + 1) % PORT_QUEUE_SIZE;
+        self.len -= 1;
+        Some(value)
+    }
+}
+
+impl Default for PortQueue {
+    fn default() -> PortQueue {
+        PortQueue::new()
+    }
+}
+
+// The world side of the ports: whatever a processor senses, does, or
+// says to its neighbors goes through here.
+pub trait PortWorld {
+    // a processor put a value on an output port
+    fn output(&mut self, port: u8, value: u8);
+    // the next value for an input port, if there is any
+    fn input(&mut self, port: u8) -> Option<u8>;
+}
+
+// Hand all output of a processor to the world and fill its input queues
+// from the world. This happens between ticks. An empty input queue that
+// gets values interrupts the processor.
+pub fn exchange<W: PortWorld>(processor: &mut Processor, world: &mut W) {
+    for port in 0..PORT_AMOUNT as u8 {
+        let output = processor.output_mut(port);
+        while let Some(value) = output.pop() {
+            world.output(port, value);
+        }
+    }
+    for port in 0..PORT_AMOUNT as u8 {
+        let input = processor.input_mut(port);
+        let was_empty = input.is_empty();
+        while !input.is_full() {
+            match world.input(port) {
+                Some(value) => {
+                    input.push(value);
+                }
+                None => break,
+            }
+        }
+        if was_empty && !input.is_empty() {
+            processor.interrupt(Event::Input(port));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Cell, Instr};
+    use std::collections::VecDeque;
+
+    // Echoes everything back on the port it came from, and has a sensor
+    // on port 1 that always gives 42.
+    struct FakeWorld {
+        outputs: Vec<(u8, u8)>,
+        echo: [VecDeque<u8>; PORT_AMOUNT],
+    }
+
+    impl FakeWorld {
+        fn new() -> FakeWorld {
+            FakeWorld {
+                outputs: Vec::new(),
+                echo: Default::default(),
+            }
+        }
+    }
+
+    impl PortWorld for FakeWorld {
+        fn output(&mut self, port: u8, value: u8) {
+            self.outputs.push((port, value));
+            self.echo[port as usize].push_back(value);
+        }
+
+        fn input(&mut self, port: u8) -> Option<u8> {
+            if port == 1 {
+                return Some(42);
+            }
+            self.echo[port as usize].pop_front()
+        }
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut q = PortQueue::new();
+        assert!(q.is_empty());
+        assert!(q.push(1));
+        assert!(q.push(2));
+        assert_eq!(q.len(), 2);
+        assert_eq!(q.pop(), Some(1));
+        assert_eq!(q.pop(), Some(2));
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let mut q = PortQueue::new();
+        for i in 0..PORT_QUEUE_SIZE {
+            assert!(q.push(i as u8));
+        }
+        assert!(q.is_full());
+        assert!(!q.push(100));
+        assert_eq!(q.pop(), Some(0));
+        // wraps around
+        assert!(q.push(100));
+        for i in 1..PORT_QUEUE_SIZE {
+            assert_eq!(q.pop(), Some(i as u8));
+        }
+        assert_eq!(q.pop(), Some(100));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn test_exchange() {
+        let mut p = Processor::new();
+        p.output_mut(2).push(5);
+        p.output_mut(2).push(6);
+        p.output_mut(0).push(7);
+        let mut world = FakeWorld::new();
+        exchange(&mut p, &mut world);
+        assert_eq!(world.outputs, vec![(0, 7), (2, 5), (2, 6)]);
+        assert_eq!(p.input_mut(2).pop(), Some(5));
+        assert_eq!(p.input_mut(2).pop(), Some(6));
+        assert_eq!(p.input_mut(2).pop(), None);
+        assert_eq!(p.input_mut(0).pop(), Some(7));
+        // the sensor filled up the queue
+        assert!(p.input_mut(1).is_full());
+    }
+
+    #[test]
+    fn test_exchange_interrupts() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Number(4), Instr::OnInput]);
+        let mut p = Processor::new();
+        p.execute(&mut c, 2);
+        let mut world = FakeWorld::new();
+        exchange(&mut p, &mut world);
+        // the sensor on port 1 filled its queue
+        assert_eq!(p.gene_index(), 4);
+        assert_eq!(p.data_stack(), &[1]);
+        // a queue that wasn't empty doesn't interrupt
+        p.input_mut(1).pop();
+        exchange(&mut p, &mut world);
+        assert_eq!(p.call_stack().len(), 1);
+    }
+
+    #[test]
+    fn test_echo_program() {
+        // read from the sensor on port 1, add one, write it to port 3
+        let mut c = Cell::new();
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(1),
+                Instr::SelectIn,
+                Instr::Number(3),
+                Instr::SelectOut,
+                Instr::In,
+                Instr::Number(1),
+                Instr::Add,
+                Instr::Out,
+            ],
+        );
+        let mut p = Processor::new();
+        let mut world = FakeWorld::new();
+        exchange(&mut p, &mut world);
+        p.execute(&mut c, 8);
+        exchange(&mut p, &mut world);
+        assert_eq!(world.outputs, vec![(3, 43)]);
+    }
+}
@@ -0,0 +1,249 @@
+use rand::Rng;
+
+use crate::data::Cell;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Crossover {
+    // each gene comes from either parent
+    GeneSwap,
+    // each gene is cut at one point: the start from the first parent, the
+    // rest from the second
+    SinglePoint,
+    // each gene is cut at two points: the middle part comes from the
+    // second parent
+    TwoPoint,
+    // each instruction comes from either parent
+    Uniform,
+}
+
+// Returns None if the parents have different configs, as their genes
+// don't line up.
+pub fn recombine<R: Rng>(a: &Cell, b: &Cell, crossover: Crossover, rng: &mut R) -> Option<Cell> {
+    if a.config() != b.config() {
+        return None;
+    }
+    let child = match crossover {
+        Crossover::GeneSwap => gene_swap(a, b, rng),
+        Crossover::SinglePoint => single_point(a, b, rng),
+        Crossover::TwoPoint => two_point(a, b, rng),
+        Crossover::Uniform => uniform(a, b, rng),
+    };
+    Some(child)
+}
+
+// The child starts out with the genes of the first parent, but not with
+// its materials or registers.
+fn child_of(a: &Cell) -> Cell {
+    let mut child = Cell::with_config(a.config());
+    for gene_index in 0..a.gene_amount() as u8 {
+        child
+            .gene_mut(gene_index)
+            .copy_from_slice(a.gene(gene_index));
+    }
+    child
+}
+
+fn gene_swap<R: Rng>(a: &Cell, b: &Cell, rng: &mut R) -> Cell {
+    let mut child = child_of(a);
+    for gene_index in 0..a.gene_amount() as u8 {
+        if rng.gen() {
+            child
+                .gene_mut(gene_index)
+                .copy_from_slice(b.gene(gene_index));
+        }
+    }
+    child
+}
+
+fn single_point<R: Rng>(a: &Cell, b: &Cell, rng: &mut R) -> Cell {
+    let mut child = child_of(a);
+    for gene_index in 0..a.gene_amount() as u8 {
+        let point = rng.gen_range(0..=a.gene_size());
+        child.gene_mut(gene_index)[point..].copy_from_slice(&b.gene(gene_index)[point..]);
+    }
+    child
+}
+
+fn two_point<R: Rng>(a: &Cell, b: &Cell, rng: &mut R) -> Cell {
+    let mut child = child_of(a);
+    for gene_index in 0..a.gene_amount() as u8 {
+        let first = rng.gen_range(0..=a.gene_size());
+        let second = rng.gen_range(0..=a.gene_size());
+        let (start, end) = if first <= second {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        child.gene_mut(gene_index)[start..end].copy_from_slice(&b.gene(gene_index)[start..end]);
+    }
+    child
+}
+
+fn uniform<R: Rng>(a: &Cell, b: &Cell, rng: &mut R) -> Cell {
+    let mut child = child_of(a);
+    for gene_index in 0..a.gene_amount() as u8 {
+        let from = b.gene(gene_index);
+        for (instr, other) in child.gene_mut(gene_index).iter_mut().zip(from.iter()) {
+            if rng.gen() {
+                *instr = *other;
+            }
+        }
+    }
+    child
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Instr, VmConfig, GENE_AMOUNT, GENE_SIZE};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn filled(instr: Instr) -> Cell {
+        let mut c = Cell::new();
+        for gene_index in 0..GENE_AMOUNT as u8 {
+            c.set_gene(gene_index, vec![instr; GENE_SIZE]);
+        }
+        c
+    }
+
+    fn count(cell: &Cell, instr: Instr) -> usize {
+        (0..GENE_AMOUNT as u8)
+            .map(|gene_index| {
+                cell.gene(gene_index)
+                    .iter()
+                    .filter(|i| **i == instr)
+                    .count()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_same_parents() {
+        let a = filled(Instr::Add);
+        let mut rng = StdRng::seed_from_u64(0);
+        for crossover in [
+            Crossover::GeneSwap,
+            Crossover::SinglePoint,
+            Crossover::TwoPoint,
+            Crossover::Uniform,
+        ]
+        .iter()
+        {
+            assert_eq!(recombine(&a, &a, *crossover, &mut rng), Some(a.clone()));
+        }
+    }
+
+    #[test]
+    fn test_child_starts_empty() {
+        let mut a = filled(Instr::Add);
+        a.add_materials(10);
+        a.set_register(2, 7);
+        let b = filled(Instr::Sub);
+        let mut rng = StdRng::seed_from_u64(0);
+        for crossover in [
+            Crossover::GeneSwap,
+            Crossover::SinglePoint,
+            Crossover::TwoPoint,
+            Crossover::Uniform,
+        ]
+        .iter()
+        {
+            let child = recombine(&a, &b, *crossover, &mut rng).unwrap();
+            assert_eq!(child.materials(), 0);
+            assert_eq!(child.register(2), 0);
+            assert_eq!(child.config(), a.config());
+        }
+    }
+
+    #[test]
+    fn test_other_config() {
+        let a = filled(Instr::Add);
+        let b = Cell::with_config(VmConfig {
+            gene_size: 4,
+            ..VmConfig::default()
+        });
+        let mut rng = StdRng::seed_from_u64(0);
+        for crossover in [
+            Crossover::GeneSwap,
+            Crossover::SinglePoint,
+            Crossover::TwoPoint,
+            Crossover::Uniform,
+        ]
+        .iter()
+        {
+            assert_eq!(recombine(&a, &b, *crossover, &mut rng), None);
+            assert_eq!(recombine(&b, &a, *crossover, &mut rng), None);
+        }
+    }
+
+    #[test]
+    fn test_gene_swap() {
+        let a = filled(Instr::Add);
+        let b = filled(Instr::Sub);
+        let child = gene_swap(&a, &b, &mut StdRng::seed_from_u64(0));
+        for gene_index in 0..GENE_AMOUNT as u8 {
+            let gene = child.gene(gene_index);
+            assert!(gene == a.gene(gene_index) || gene == b.gene(gene_index));
+        }
+        assert!(count(&child, Instr::Add) > 0);
+        assert!(count(&child, Instr::Sub) > 0);
+    }
+
+    #[test]
+    fn test_single_point() {
+        let a = filled(Instr::Add);
+        let b = filled(Instr::Sub);
+        let child = single_point(&a, &b, &mut StdRng::seed_from_u64(0));
+        for gene_index in 0..GENE_AMOUNT as u8 {
+            let gene = child.gene(gene_index);
+            let point = gene
+                .iter()
+                .position(|instr| *instr == Instr::Sub)
+                .unwrap_or(GENE_SIZE);
+            assert!(gene[..point].iter().all(|instr| *instr == Instr::Add));
+            assert!(gene[point..].iter().all(|instr| *instr == Instr::Sub));
+        }
+    }
+
+    #[test]
+    fn test_two_point() {
+        let a = filled(Instr::Add);
+        let b = filled(Instr::Sub);
+        let child = two_point(&a, &b, &mut StdRng::seed_from_u64(0));
+        for gene_index in 0..GENE_AMOUNT as u8 {
+            let gene = child.gene(gene_index);
+            let start = gene
+                .iter()
+                .position(|instr| *instr == Instr::Sub)
+                .unwrap_or(GENE_SIZE);
+            let end = gene
+                .iter()
+                .rposition(|instr| *instr == Instr::Sub)
+                .map_or(start, |position| position + 1);
+            assert!(gene[..start].iter().all(|instr| *instr == Instr::Add));
+            assert!(gene[start..end].iter().all(|instr| *instr == Instr::Sub));
+            assert!(gene[end..].iter().all(|instr| *instr == Instr::Add));
+        }
+    }
+
+    #[test]
+    fn test_uniform() {
+        let a = filled(Instr::Add);
+        let b = filled(Instr::Sub);
+        let child = uniform(&a, &b, &mut StdRng::seed_from_u64(0));
+        let adds = count(&child, Instr::Add);
+        let subs = count(&child, Instr::Sub);
+        assert_eq!(adds + subs, GENE_SIZE * GENE_AMOUNT);
+        assert!(adds > 0 && subs > 0);
+    }
+
+    #[test]
+    fn test_recombine_deterministic() {
+        let a = filled(Instr::Add);
+        let b = filled(Instr::Sub);
+        let first = recombine(&a, &b, Crossover::Uniform, &mut StdRng::seed_from_u64(7));
+        let second = recombine(&a, &b, Crossover::Uniform, &mut StdRng::seed_from_u64(7));
+        assert_eq!(first, second);
+    }
+}
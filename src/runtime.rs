@@ -0,0 +1,575 @@
+use crate::data::{mix, Cell, Event, Instr, Processor, PROCESSOR_AMOUNT};
+use crate::encoding::OPCODES;
+use crate::ports::{self, PortWorld};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Scheduling {
+    // every active processor gets the same share of the budget
+    RoundRobin,
+    // every active processor gets a share in proportion to its weight
+    Weighted,
+}
+
+// The energy it takes to execute each instruction, by opcode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CostTable {
+    costs: [u32; OPCODES.len()],
+}
+
+impl CostTable {
+    pub fn uniform(cost: u32) -> CostTable {
+        CostTable {
+            costs: [cost; OPCODES.len()],
+        }
+    }
+
+    pub fn cost(&self, instr: Instr) -> u32 {
+        self.costs[instr.opcode() as usize]
+    }
+
+    // The cost is the same for all numbers.
+    pub fn set_cost(&mut self, instr: Instr, cost: u32) {
+        self.costs[instr.opcode() as usize] = cost;
+    }
+}
+
+impl Default for CostTable {
+    fn default() -> CostTable {
+        CostTable::uniform(1)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct TickReport {
+    pub executed: usize,
+    pub energy_spent: u32,
+}
+
+// A cell with the processors running on it. Each tick the instruction
+// budget is divided between the active processors. Instructions cost
+// energy; a processor halts for the rest of the tick when the cell
+// doesn't have enough energy left for its next instruction.
+//
+// The processors run one after the other, in an order that only depends
+// on the ticks so far, so what they see of each other through the
+// registers of the cell is deterministic.
+#[derive(Debug, Clone)]
+pub struct CellRuntime {
+    cell: Cell,
+    processors: [Processor; PROCESSOR_AMOUNT],
+    weights: [u8; PROCESSOR_AMOUNT],
+    scheduling: Scheduling,
+    costs: CostTable,
+    energy: u32,
+    // the processor that gets the first left-over instruction
+    next: usize,
+}
+
+impl CellRuntime {
+    // The cell starts with a single processor running gene 0.
+    pub fn new(cell: Cell) -> CellRuntime {
+        let config = cell.config();
+        let processors = std::array::from_fn(|i| {
+            let mut processor = Processor::with_config(config);
+            if i > 0 {
+                processor.stop();
+            }
+            processor
+        });
+        CellRuntime {
+            cell,
+            processors,
+            weights: [1; PROCESSOR_AMOUNT],
+            scheduling: Scheduling::RoundRobin,
+            costs: CostTable::default(),
+            energy: 0,
+            next: 0,
+        }
+    }
+
+    pub fn cell(&self) -> &Cell {
+        &self.cell
+    }
+
+    pub fn cell_mut(&mut self) -> &mut Cell {
+        &mut self.cell
+    }
+
+    pub fn processor(&self, index: usize) -> &Processor {
+        &self.processors[index]
+    }
+
+    pub fn processor_mut(&mut self, index: usize) -> &mut Processor {
+        &mut self.processors[index]
+    }
+
+    pub fn set_scheduling(&mut self, scheduling: Scheduling) {
+        self.scheduling = scheduling;
+    }
+
+    pub fn set_weight(&mut self, index: usize, weight: u8) {
+        self.weights[index] = weight;
+    }
+
+    // Seed the random number generators of the processors. Each processor
+    // gets its own sequence, also when cells get seeds that are close
+    // together.
+    pub fn set_seed(&mut self, seed: u64) {
+        for (i, processor) in self.processors.iter_mut().enumerate() {
+            processor.set_seed(seed_for(seed, i as u64));
+        }
+    }
+
+    pub fn set_costs(&mut self, costs: CostTable) {
+        self.costs = costs;
+    }
+
+    pub fn energy(&self) -> u32 {
+        self.energy
+    }
+
+    pub fn add_energy(&mut self, amount: u32) {
+        self.energy = self.energy.saturating_add(amount);
+    }
+
+    pub fn active_amount(&self) -> usize {
+        self.processors.iter().filter(|p| p.is_active()).count()
+    }
+
+    // Start a processor on a gene in the first free slot. Returns the
+    // slot, or None if all processors are already running.
+    pub fn start(&mut self, gene_index: u8) -> Option<usize> {
+        let index = self.processors.iter().position(|p| !p.is_active())?;
+        self.processors[index].start(gene_index);
+        Some(index)
+    }
+
+    pub fn stop(&mut self, index: usize) {
+        self.processors[index].stop();
+    }
+
+    // Something started touching a side of the cell. All processors that
+    // registered a gene for it get interrupted.
+    pub fn contact(&mut self, side: u8) {
+        for processor in self.processors.iter_mut() {
+            processor.interrupt(Event::Contact(side));
+        }
+    }
+
+    // Hand the output of all processors to the world and fill their input
+    // queues from it, see ports::exchange. This happens between ticks.
+    pub fn exchange<W: PortWorld>(&mut self, world: &mut W) {
+        for processor in self.processors.iter_mut() {
+            ports::exchange(processor, world);
+        }
+    }
+
+    // Run the processors for a tick. The processors take turns being the
+    // first to spend energy.
+    pub fn tick(&mut self, budget: usize) -> TickReport {
+        for processor in self.processors.iter_mut() {
+            processor.tick_timer();
+        }
+        let shares = self.shares(budget);
+        let mut report = TickReport::default();
+        for offset in 0..PROCESSOR_AMOUNT {
+            let index = (self.next + offset) % PROCESSOR_AMOUNT;
+            let processor = &mut self.processors[index];
+            for _i in 0..shares[index] {
+                let cost = self.costs.cost(processor.next_instruction(&self.cell));
+                if cost > self.energy {
+                    break;
+                }
+                self.energy -= cost;
+                processor.step(&mut self.cell);
+                report.executed += 1;
+                report.energy_spent += cost;
+                // the processor faulted
+                if !processor.is_active() {
+                    break;
+                }
+            }
+        }
+        self.next = (self.next + 1) % PROCESSOR_AMOUNT;
+        report
+    }
+
+    fn shares(&self, budget: usize) -> [usize; PROCESSOR_AMOUNT] {
+        let mut weights = [0; PROCESSOR_AMOUNT];
+        for (i, processor) in self.processors.iter().enumerate() {
+            if processor.is_active() {
+                weights[i] = match self.scheduling {
+                    Scheduling::RoundRobin => 1,
+                    Scheduling::Weighted => self.weights[i] as usize,
+                };
+            }
+        }
+        let total: usize = weights.iter().sum();
+        let mut shares = [0; PROCESSOR_AMOUNT];
+        if total == 0 {
+            return shares;
+        }
+        for i in 0..PROCESSOR_AMOUNT {
+            shares[i] = budget * weights[i] / total;
+        }
+        // hand out what's left one by one, starting with a different
+        // processor each tick so it's fair over time
+        let mut remainder = budget - shares.iter().sum::<usize>();
+        let mut i = self.next;
+        while remainder > 0 {
+            if weights[i] > 0 {
+                shares[i] += 1;
+                remainder -= 1;
+            }
+            i = (i + 1) % PROCESSOR_AMOUNT;
+        }
+        shares
+    }
+}
+
+// A seed for the index-th thing seeded from seed. Different indexes give
+// different seeds.
+pub fn seed_for(seed: u64, index: u64) -> u64 {
+    mix(seed ^ mix(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Instr, StackPolicy, VmConfig};
+
+    fn counting_cell() -> Cell {
+        // each gene counts the instructions executed on it
+        let mut c = Cell::new();
+        for gene_index in 0..4 {
+            c.set_gene(
+                gene_index,
+                vec![
+                    Instr::Number(0),
+                    Instr::Number(0),
+                    Instr::Label,
+                    Instr::Number(1),
+                    Instr::Add,
+                    Instr::Number(0),
+                    Instr::Jump,
+                ],
+            );
+        }
+        c
+    }
+
+    #[test]
+    fn test_new() {
+        let r = CellRuntime::new(Cell::new());
+        assert_eq!(r.active_amount(), 1);
+        assert!(r.processor(0).is_active());
+    }
+
+    #[test]
+    fn test_tick_fault() {
+        let config = VmConfig {
+            stack_policy: StackPolicy::Fault,
+            ..VmConfig::default()
+        };
+        let mut c = Cell::with_config(config);
+        c.set_gene(0, vec![Instr::Number(1), Instr::Drop, Instr::Drop]);
+        let mut r = CellRuntime::new(c);
+        r.add_energy(100);
+        assert_eq!(
+            r.tick(10),
+            TickReport {
+                executed: 3,
+                energy_spent: 3
+            }
+        );
+        assert_eq!(r.active_amount(), 0);
+        assert_eq!(r.processor(0).faults(), 1);
+    }
+
+    #[test]
+    fn test_contact() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Number(1), Instr::OnContact]);
+        c.set_gene(1, vec![Instr::Number(1), Instr::Add]);
+        let mut r = CellRuntime::new(c);
+        r.add_energy(100);
+        r.tick(2);
+        r.contact(3);
+        r.tick(2);
+        assert_eq!(r.processor(0).data_stack(), &[4]);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Number(1), Instr::Number(2), Instr::SetTimer]);
+        c.set_gene(1, vec![Instr::Number(9)]);
+        let mut r = CellRuntime::new(c);
+        r.add_energy(100);
+        r.tick(3);
+        // the timer counts ticks, not instructions
+        r.tick(1);
+        assert_eq!(r.processor(0).gene_index(), 0);
+        r.tick(1);
+        assert_eq!(r.processor(0).data_stack(), &[9]);
+    }
+
+    // Gives 5 on port 2 once.
+    struct Sensor(bool);
+
+    impl PortWorld for Sensor {
+        fn output(&mut self, _port: u8, _value: u8) {}
+
+        fn input(&mut self, port: u8) -> Option<u8> {
+            if port != 2 || self.0 {
+                return None;
+            }
+            self.0 = true;
+            Some(5)
+        }
+    }
+
+    #[test]
+    fn test_exchange() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Number(3), Instr::OnInput]);
+        let mut r = CellRuntime::new(c);
+        r.add_energy(100);
+        r.tick(2);
+        r.exchange(&mut Sensor(false));
+        assert_eq!(r.processor(0).gene_index(), 3);
+        assert_eq!(r.processor(0).data_stack(), &[2]);
+        assert_eq!(r.processor_mut(0).input_mut(2).pop(), Some(5));
+    }
+
+    fn lock_runtime() -> CellRuntime {
+        // whoever gets the lock in register 0 writes its gene index to
+        // register 1; the others keep trying
+        let mut c = Cell::new();
+        for gene_index in 0..2 {
+            c.set_gene(
+                gene_index,
+                vec![
+                    Instr::Number(0),
+                    Instr::TestAndSet,
+                    Instr::Cond,
+                    Instr::Return,
+                    Instr::Number(gene_index),
+                    Instr::Number(1),
+                    Instr::Store,
+                ],
+            );
+        }
+        let mut r = CellRuntime::new(c);
+        r.add_energy(100);
+        r.start(1);
+        r
+    }
+
+    #[test]
+    fn test_lock() {
+        let mut r = lock_runtime();
+        r.tick(14);
+        assert_eq!(r.cell().register(0), 1);
+        assert_eq!(r.cell().register(1), 0);
+        // processor 1 goes first in the next tick
+        let mut r = lock_runtime();
+        r.tick(0);
+        r.tick(14);
+        assert_eq!(r.cell().register(1), 1);
+    }
+
+    #[test]
+    fn test_start_and_stop() {
+        let mut r = CellRuntime::new(Cell::new());
+        assert_eq!(r.start(3), Some(1));
+        assert_eq!(r.start(3), Some(2));
+        assert_eq!(r.start(3), Some(3));
+        assert_eq!(r.start(3), None);
+        assert_eq!(r.active_amount(), PROCESSOR_AMOUNT);
+        r.stop(2);
+        assert_eq!(r.active_amount(), PROCESSOR_AMOUNT - 1);
+        assert_eq!(r.start(5), Some(2));
+    }
+
+    #[test]
+    fn test_tick_single() {
+        let mut r = CellRuntime::new(counting_cell());
+        r.add_energy(100);
+        assert_eq!(
+            r.tick(10),
+            TickReport {
+                executed: 10,
+                energy_spent: 10
+            }
+        );
+        assert_eq!(r.energy(), 90);
+    }
+
+    #[test]
+    fn test_tick_no_processors() {
+        let mut r = CellRuntime::new(counting_cell());
+        r.add_energy(100);
+        r.stop(0);
+        assert_eq!(r.tick(10), TickReport::default());
+        assert_eq!(r.energy(), 100);
+    }
+
+    #[test]
+    fn test_tick_no_energy() {
+        let mut r = CellRuntime::new(counting_cell());
+        assert_eq!(r.tick(10), TickReport::default());
+        assert_eq!(r.processor(0), &Processor::new());
+    }
+
+    #[test]
+    fn test_tick_runs_out_of_energy() {
+        let mut r = CellRuntime::new(counting_cell());
+        r.add_energy(4);
+        assert_eq!(
+            r.tick(10),
+            TickReport {
+                executed: 4,
+                energy_spent: 4
+            }
+        );
+        assert_eq!(r.energy(), 0);
+        r.add_energy(1);
+        assert_eq!(r.tick(10).executed, 1);
+    }
+
+    #[test]
+    fn test_tick_costs() {
+        let mut costs = CostTable::uniform(0);
+        costs.set_cost(Instr::Add, 5);
+        costs.set_cost(Instr::Number(0), 2);
+        assert_eq!(costs.cost(Instr::Number(100)), 2);
+        let mut r = CellRuntime::new(counting_cell());
+        r.set_costs(costs);
+        r.add_energy(8);
+        // =0 =0 Label =1 Add; the Add costs too much
+        let report = r.tick(10);
+        assert_eq!(
+            report,
+            TickReport {
+                executed: 4,
+                energy_spent: 6
+            }
+        );
+        assert_eq!(r.processor(0).next_instruction(r.cell()), Instr::Add);
+    }
+
+    #[test]
+    fn test_tick_energy_turns() {
+        let mut r = CellRuntime::new(counting_cell());
+        r.start(0);
+        r.add_energy(3);
+        r.tick(10);
+        // the first processor got to spend all energy
+        assert_eq!(r.processor(1), &Processor::new());
+        r.add_energy(3);
+        r.tick(10);
+        // now it's the second processor's turn
+        assert_eq!(r.processor(0), r.processor(1));
+    }
+
+    #[test]
+    fn test_round_robin_shares() {
+        let mut r = CellRuntime::new(counting_cell());
+        r.start(1);
+        r.start(2);
+        assert_eq!(r.shares(9), [3, 3, 3, 0]);
+        // the left-overs move around
+        assert_eq!(r.shares(10), [4, 3, 3, 0]);
+        r.tick(10);
+        assert_eq!(r.shares(10), [3, 4, 3, 0]);
+        r.tick(10);
+        assert_eq!(r.shares(10), [3, 3, 4, 0]);
+    }
+
+    #[test]
+    fn test_weighted_shares() {
+        let mut r = CellRuntime::new(counting_cell());
+        r.start(1);
+        r.set_scheduling(Scheduling::Weighted);
+        r.set_weight(0, 3);
+        r.set_weight(1, 1);
+        assert_eq!(r.shares(8), [6, 2, 0, 0]);
+        // weight of stopped processors doesn't count
+        r.set_weight(2, 5);
+        assert_eq!(r.shares(8), [6, 2, 0, 0]);
+    }
+
+    #[test]
+    fn test_weighted_zero_weight() {
+        let mut r = CellRuntime::new(counting_cell());
+        r.start(1);
+        r.set_scheduling(Scheduling::Weighted);
+        r.set_weight(1, 0);
+        assert_eq!(r.shares(7), [7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_seed() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Rand]);
+        let mut r = CellRuntime::new(c.clone());
+        r.set_seed(7);
+        r.add_energy(100);
+        r.start(0);
+        r.tick(10);
+        // the processors draw different numbers
+        assert_ne!(r.processor(0), r.processor(1));
+        // but the same seed gives the same run
+        let mut other = CellRuntime::new(c);
+        other.set_seed(7);
+        other.add_energy(100);
+        other.start(0);
+        other.tick(10);
+        assert_eq!(r.processor(0), other.processor(0));
+        assert_eq!(r.processor(1), other.processor(1));
+    }
+
+    #[test]
+    fn test_seed_for() {
+        let mut seeds: Vec<u64> = (0..64)
+            .flat_map(|seed| (0..PROCESSOR_AMOUNT as u64).map(move |i| seed_for(seed, i)))
+            .collect();
+        seeds.sort_unstable();
+        seeds.dedup();
+        assert_eq!(seeds.len(), 64 * PROCESSOR_AMOUNT);
+    }
+
+    #[test]
+    fn test_seed_next_cell() {
+        let mut c = Cell::new();
+        c.set_gene(0, vec![Instr::Rand]);
+        let runtimes: Vec<CellRuntime> = (7..9)
+            .map(|seed| {
+                let mut r = CellRuntime::new(c.clone());
+                r.set_seed(seed);
+                r.add_energy(100);
+                r.start(0);
+                r.tick(10);
+                r
+            })
+            .collect();
+        // processor 1 of one cell doesn't draw what processor 0 of the
+        // next one draws
+        assert_ne!(
+            runtimes[0].processor(1).data_stack(),
+            runtimes[1].processor(0).data_stack()
+        );
+    }
+
+    #[test]
+    fn test_tick_runs_processors() {
+        let mut r = CellRuntime::new(counting_cell());
+        r.add_energy(100);
+        r.start(0);
+        r.tick(100);
+        // both processors ran the same loop for the same amount of time
+        assert_eq!(r.processor(0), r.processor(1));
+        assert!(r.processor(0) != &Processor::new());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::data::Instr;
+use crate::encoding::OPCODES;
+
+// What a processor did since its statistics were last reset.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Statistics {
+    executed: u64,
+    skipped: u64,
+    underflows: u64,
+    overflows: u64,
+    call_resets: u64,
+    // by opcode
+    histogram: [u64; OPCODES.len()],
+}
+
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics {
+            executed: 0,
+            skipped: 0,
+            underflows: 0,
+            overflows: 0,
+            call_resets: 0,
+            histogram: [0; OPCODES.len()],
+        }
+    }
+
+    // All steps, including the skipped ones.
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    // Steps where cond was false, so the instruction did nothing.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    // Pops from an empty stack.
+    pub fn underflows(&self) -> u64 {
+        self.underflows
+    }
+
+    // Pushes on a full stack, whatever the stack policy did about it.
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    // Returns from the main gene, which restart the processor.
+    pub fn call_resets(&self) -> u64 {
+        self.call_resets
+    }
+
+    // How often the instruction was executed. All numbers count as one
+    // instruction.
+    pub fn count(&self, instr: Instr) -> u64 {
+        self.histogram[instr.opcode() as usize]
+    }
+
+    // The counts by opcode.
+    pub fn histogram(&self) -> &[u64] {
+        &self.histogram
+    }
+
+    pub(crate) fn record_step(&mut self, opcode: u8, skipped: bool) {
+        self.executed += 1;
+        if skipped {
+            self.skipped += 1;
+        }
+        self.histogram[opcode as usize] += 1;
+    }
+
+    pub(crate) fn record_underflow(&mut self) {
+        self.underflows += 1;
+    }
+
+    pub(crate) fn record_overflow(&mut self) {
+        self.overflows += 1;
+    }
+
+    pub(crate) fn record_call_reset(&mut self) {
+        self.call_resets += 1;
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Statistics {
+        Statistics::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Cell, Processor, VmConfig};
+
+    fn config() -> VmConfig {
+        VmConfig {
+            gene_size: 4,
+            data_stack_size: 2,
+            ..VmConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_statistics() {
+        let mut c = Cell::with_config(config());
+        c.set_gene(
+            0,
+            vec![Instr::Number(0), Instr::Cond, Instr::Add, Instr::Drop],
+        );
+        let mut p = Processor::with_config(config());
+        // the fifth step returns from the main gene and starts again
+        p.execute(&mut c, 5);
+        let statistics = p.statistics();
+        assert_eq!(statistics.executed(), 5);
+        assert_eq!(statistics.skipped(), 1);
+        assert_eq!(statistics.underflows(), 1);
+        assert_eq!(statistics.overflows(), 0);
+        assert_eq!(statistics.call_resets(), 1);
+        assert_eq!(statistics.count(Instr::Number(7)), 2);
+        assert_eq!(statistics.count(Instr::Add), 1);
+        assert_eq!(statistics.count(Instr::Sub), 0);
+        assert_eq!(statistics.histogram().iter().sum::<u64>(), 5);
+    }
+
+    #[test]
+    fn test_statistics_overflow() {
+        let mut c = Cell::with_config(config());
+        c.set_gene(0, vec![Instr::Number(1); 4]);
+        let mut p = Processor::with_config(config());
+        p.execute(&mut c, 4);
+        assert_eq!(p.statistics().overflows(), 2);
+        assert_eq!(p.statistics().underflows(), 0);
+    }
+
+    #[test]
+    fn test_reset_statistics() {
+        let mut c = Cell::with_config(config());
+        c.set_gene(0, vec![Instr::Drop]);
+        let mut p = Processor::with_config(config());
+        p.execute(&mut c, 2);
+        // restarting keeps the statistics
+        p.start(0);
+        assert_eq!(p.statistics().executed(), 2);
+        p.reset_statistics();
+        assert_eq!(p.statistics(), &Statistics::new());
+        p.execute(&mut c, 1);
+        assert_eq!(p.statistics().executed(), 1);
+        assert_eq!(p.statistics().underflows(), 1);
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::{self, Write};
+
+use crate::data::Instr;
+
+// What happened in a single step of a processor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub gene_index: u8,
+    pub pc: usize,
+    pub instr: Instr,
+    // cond was false, so the instruction did nothing
+    pub skipped: bool,
+    // data stack depth
+    pub depth_before: usize,
+    pub depth_after: usize,
+    // the amount of callers, before the instruction
+    pub call_depth: usize,
+}
+
+pub trait Tracer {
+    fn trace(&mut self, record: &TraceRecord);
+}
+
+// Traces nothing. As tracing is generic over the tracer this compiles away
+// entirely.
+pub struct NoTracer;
+
+impl Tracer for NoTracer {
+    #[inline(always)]
+    fn trace(&mut self, _record: &TraceRecord) {}
+}
+
+impl Tracer for Vec<TraceRecord> {
+    fn trace(&mut self, record: &TraceRecord) {
+        self.push(*record);
+    }
+}
+
+impl TraceRecord {
+    // A single line of JSON, without the newline.
+    pub fn to_json(&self) -> String {
+        // the instruction is in assembler syntax, which needs no escaping
+        format!(
+            "{{\"gene\":{},\"pc\":{},\"instr\":\"{}\",\"skipped\":{},\"depth_before\":{},\"depth_after\":{},\"call_depth\":{}}}",
+            self.gene_index,
+            self.pc,
+            self.instr,
+            self.skipped,
+            self.depth_before,
+            self.depth_after,
+            self.call_depth
+        )
+    }
+}
+
+// Writes a JSON Lines trace, one object per executed instruction.
+pub struct JsonLinesTracer<W: Write> {
+    writer: W,
+    // tracing can't fail halfway, so we keep the first error for finish
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonLinesTracer<W> {
+    pub fn new(writer: W) -> JsonLinesTracer<W> {
+        JsonLinesTracer {
+            writer,
+            error: None,
+        }
+    }
+
+    // Flushes the writer and returns it, or the first error that happened
+    // while tracing.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Tracer for JsonLinesTracer<W> {
+    fn trace(&mut self, record: &TraceRecord) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(err) = writeln!(self.writer, "{}", record.to_json()) {
+            self.error = Some(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Cell, Processor};
+
+    fn sample_cell() -> Cell {
+        let mut c = Cell::new();
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(5),
+                Instr::Number(1),
+                Instr::Call,
+                Instr::Number(0),
+                Instr::Cond,
+                Instr::Add,
+            ],
+        );
+        c.set_gene(1, vec![Instr::Number(3), Instr::Add, Instr::Return]);
+        c
+    }
+
+    #[test]
+    fn test_trace_records() {
+        let mut c = sample_cell();
+        let mut p = Processor::new();
+        let mut records = Vec::new();
+        p.execute_traced(&mut c, 9, &mut records);
+        assert_eq!(records.len(), 9);
+        assert_eq!(
+            records[3],
+            TraceRecord {
+                gene_index: 1,
+                pc: 0,
+                instr: Instr::Number(3),
+                skipped: false,
+                depth_before: 1,
+                depth_after: 2,
+                call_depth: 1,
+            }
+        );
+        // the Add after a false Cond is skipped
+        assert_eq!(
+            records[8],
+            TraceRecord {
+                gene_index: 0,
+                pc: 5,
+                instr: Instr::Add,
+                skipped: true,
+                depth_before: 1,
+                depth_after: 1,
+                call_depth: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_trace_does_not_change_execution() {
+        let mut traced_cell = sample_cell();
+        let mut traced = Processor::new();
+        traced.execute_traced(&mut traced_cell, 100, &mut Vec::new());
+        let mut c = sample_cell();
+        let mut p = Processor::new();
+        p.execute(&mut c, 100);
+        assert_eq!(traced, p);
+        assert_eq!(traced_cell, c);
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let mut c = sample_cell();
+        let mut p = Processor::new();
+        let mut tracer = JsonLinesTracer::new(Vec::new());
+        p.execute_traced(&mut c, 2, &mut tracer);
+        let output = String::from_utf8(tracer.finish().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "{\"gene\":0,\"pc\":0,\"instr\":\"=5\",\"skipped\":false,\"depth_before\":0,\"depth_after\":1,\"call_depth\":0}
+{\"gene\":0,\"pc\":1,\"instr\":\"=1\",\"skipped\":false,\"depth_before\":1,\"depth_after\":2,\"call_depth\":0}
+"
+        );
+    }
+
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_lines_error() {
+        let mut c = sample_cell();
+        let mut p = Processor::new();
+        let mut tracer = JsonLinesTracer::new(FailingWriter);
+        p.execute_traced(&mut c, 3, &mut tracer);
+        // execution carries on
+        assert_eq!(p.pc(), 0);
+        assert!(tracer.finish().is_err());
+    }
+}
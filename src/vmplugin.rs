@@ -0,0 +1,307 @@
+use bevy::prelude::*;
+use bevy::tasks::{ComputeTaskPool, ParallelIterator};
+
+use crate::data::PORT_AMOUNT;
+use crate::ports::{PortQueue, PortWorld};
+use crate::runtime::{seed_for, CellRuntime, TickReport};
+
+// Runs before the update stage, so systems such as the thrusters see what
+// the cells did this frame.
+pub const VM_STAGE: &str = "vm";
+
+// Runs before the VM stage, so what the world put in the ports during the
+// last frame reaches the cells before they tick.
+pub const PORT_STAGE: &str = "ports";
+
+// How many cells a task runs at a time.
+const BATCH_SIZE: usize = 64;
+
+// The instructions each cell gets per tick, divided between its
+// processors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InstructionBudget(pub usize);
+
+impl Default for InstructionBudget {
+    fn default() -> InstructionBudget {
+        InstructionBudget(100)
+    }
+}
+
+// The energy each cell gets per frame, before it ticks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EnergyIncome(pub u32);
+
+impl Default for EnergyIncome {
+    fn default() -> EnergyIncome {
+        EnergyIncome(100)
+    }
+}
+
+// What all cells together did during the last frame, added up from the
+// TickReport of each cell.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct WorldReport {
+    pub cells: usize,
+    pub executed: usize,
+    pub energy_spent: u64,
+}
+
+// Where the random number generators of new cells get their seeds from,
+// so a world started with the same seed plays out the same way. Each
+// spawned cell gets its own seed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct WorldSeed {
+    seed: u64,
+    spawned: u64,
+}
+
+impl WorldSeed {
+    pub fn new(seed: u64) -> WorldSeed {
+        WorldSeed { seed, spawned: 0 }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // The seed for the next cell that is spawned.
+    pub fn next_seed(&mut self) -> u64 {
+        let seed = seed_for(self.seed, self.spawned);
+        self.spawned += 1;
+        seed
+    }
+}
+
+// The world side of the ports of a cell. Other systems put values in
+// inputs for the cell and take what it said from outputs; values that
+// don't fit are dropped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Ports {
+    pub inputs: [PortQueue; PORT_AMOUNT],
+    pub outputs: [PortQueue; PORT_AMOUNT],
+}
+
+impl PortWorld for Ports {
+    fn output(&mut self, port: u8, value: u8) {
+        self.outputs[port as usize % PORT_AMOUNT].push(value);
+    }
+
+    fn input(&mut self, port: u8) -> Option<u8> {
+        self.inputs[port as usize % PORT_AMOUNT].pop()
+    }
+}
+
+pub struct VmPlugin;
+
+impl Plugin for VmPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<InstructionBudget>()
+            .init_resource::<EnergyIncome>()
+            .init_resource::<WorldSeed>()
+            .init_resource::<WorldReport>()
+            .add_stage_before(stage::UPDATE, VM_STAGE, SystemStage::parallel())
+            .add_stage_before(VM_STAGE, PORT_STAGE, SystemStage::parallel())
+            .add_system_to_stage(PORT_STAGE, port_system.system())
+            .add_system_to_stage(PORT_STAGE, energy_system.system())
+            .add_system_to_stage(VM_STAGE, vm_system.system())
+            .add_system_to_stage(stage::UPDATE, report_system.system());
+    }
+}
+
+// Tick every cell and keep what it did in its TickReport. Cells only
+// change themselves, so the result doesn't depend on how the cells are
+// spread over the threads.
+pub fn vm_system(
+    pool: Res<ComputeTaskPool>,
+    budget: Res<InstructionBudget>,
+    mut cells: Query<(&mut CellRuntime, &mut TickReport)>,
+) {
+    let budget = budget.0;
+    cells
+        .par_iter_mut(BATCH_SIZE)
+        .for_each(&pool, |(mut runtime, mut report)| {
+            *report = runtime.tick(budget);
+        });
+}
+
+// Give every cell its energy for this frame.
+pub fn energy_system(income: Res<EnergyIncome>, mut runtimes: Query<&mut CellRuntime>) {
+    for mut runtime in runtimes.iter_mut() {
+        runtime.add_energy(income.0);
+    }
+}
+
+// Add up the reports of the cells for the world.
+pub fn report_system(mut world_report: ResMut<WorldReport>, reports: Query<&TickReport>) {
+    let mut total = WorldReport::default();
+    for report in reports.iter() {
+        total.cells += 1;
+        total.executed += report.executed;
+        total.energy_spent += report.energy_spent as u64;
+    }
+    *world_report = total;
+}
+
+// Exchange the port values between the cells and the world, which may
+// interrupt processors waiting for input.
+pub fn port_system(mut cells: Query<(&mut CellRuntime, &mut Ports)>) {
+    for (mut runtime, mut ports) in cells.iter_mut() {
+        runtime.exchange(&mut *ports);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Cell, Instr};
+    use bevy::ecs::Stage;
+    use bevy::tasks::TaskPoolBuilder;
+
+    fn runtime(seed: u64) -> CellRuntime {
+        let mut c = Cell::new();
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(255),
+                Instr::RandRange,
+                Instr::Number(0),
+                Instr::Store,
+            ],
+        );
+        let mut runtime = CellRuntime::new(c);
+        runtime.set_seed(seed);
+        runtime.add_energy(1000);
+        runtime
+    }
+
+    fn run(threads: usize) -> Vec<(CellRuntime, TickReport)> {
+        let mut world = World::new();
+        let mut resources = Resources::default();
+        resources.insert(ComputeTaskPool(
+            TaskPoolBuilder::new().num_threads(threads).build(),
+        ));
+        resources.insert(InstructionBudget(10));
+        let entities: Vec<Entity> = (0..200)
+            .map(|seed| world.spawn((runtime(seed), TickReport::default())))
+            .collect();
+        let mut stage = SystemStage::parallel();
+        stage.add_system(vm_system.system());
+        stage.initialize(&mut world, &mut resources);
+        for _i in 0..3 {
+            stage.run(&mut world, &mut resources);
+        }
+        entities
+            .iter()
+            .map(|entity| {
+                let runtime = world.get::<CellRuntime>(*entity).unwrap().clone();
+                (runtime, *world.get::<TickReport>(*entity).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_vm_system() {
+        let runtimes = run(4);
+        for (seed, (runtime, report)) in runtimes.iter().enumerate() {
+            let mut expected = self::runtime(seed as u64);
+            for _i in 0..3 {
+                expected.tick(10);
+            }
+            assert_eq!(runtime.energy(), 970);
+            assert_eq!(
+                *report,
+                TickReport {
+                    executed: 10,
+                    energy_spent: 10
+                }
+            );
+            assert_eq!(runtime.cell(), expected.cell());
+            assert_eq!(runtime.processor(0), expected.processor(0));
+        }
+    }
+
+    #[test]
+    fn test_energy_and_report_system() {
+        let mut world = World::new();
+        let mut resources = Resources::default();
+        resources.insert(EnergyIncome(5));
+        resources.insert(WorldReport::default());
+        for seed in 0..3 {
+            world.spawn((
+                runtime(seed),
+                TickReport {
+                    executed: 4,
+                    energy_spent: 6,
+                },
+            ));
+        }
+        let mut stage = SystemStage::parallel();
+        stage.add_system(energy_system.system());
+        stage.add_system(report_system.system());
+        stage.initialize(&mut world, &mut resources);
+        stage.run(&mut world, &mut resources);
+        for runtime in world.query::<&CellRuntime>() {
+            assert_eq!(runtime.energy(), 1005);
+        }
+        assert_eq!(
+            *resources.get::<WorldReport>().unwrap(),
+            WorldReport {
+                cells: 3,
+                executed: 12,
+                energy_spent: 18,
+            }
+        );
+    }
+
+    #[test]
+    fn test_world_seed() {
+        let mut a = WorldSeed::new(3);
+        let mut b = WorldSeed::new(3);
+        let first = a.next_seed();
+        assert_eq!(first, b.next_seed());
+        assert_ne!(first, a.next_seed());
+        assert_ne!(first, WorldSeed::new(4).next_seed());
+    }
+
+    #[test]
+    fn test_port_system() {
+        let mut c = Cell::new();
+        c.set_gene(
+            0,
+            vec![
+                Instr::Number(1),
+                Instr::OnInput,
+                Instr::Number(9),
+                Instr::Out,
+            ],
+        );
+        let mut runtime = CellRuntime::new(c);
+        runtime.add_energy(100);
+        runtime.tick(4);
+        let mut ports = Ports::default();
+        ports.inputs[3].push(7);
+        let mut world = World::new();
+        let mut resources = Resources::default();
+        let entity = world.spawn((runtime, ports));
+        let mut stage = SystemStage::parallel();
+        stage.add_system(port_system.system());
+        stage.initialize(&mut world, &mut resources);
+        stage.run(&mut world, &mut resources);
+        let runtime = world.get::<CellRuntime>(entity).unwrap();
+        assert_eq!(runtime.processor(0).gene_index(), 1);
+        assert_eq!(runtime.processor(0).data_stack(), &[3]);
+        let mut ports = world.get_mut::<Ports>(entity).unwrap();
+        assert_eq!(ports.outputs[0].pop(), Some(9));
+        assert!(ports.inputs[3].is_empty());
+    }
+
+    #[test]
+    fn test_vm_system_thread_count() {
+        let one = run(1);
+        let many = run(3);
+        for ((a, _), (b, _)) in one.iter().zip(&many) {
+            assert_eq!(a.cell(), b.cell());
+            assert_eq!(a.processor(0), b.processor(0));
+        }
+    }
+}